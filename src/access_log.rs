@@ -0,0 +1,183 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::http::RequestHead;
+
+// events with this target go to the access log file instead of the server log
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+const USER_AGENT: &str = "User-Agent";
+const REFERER: &str = "Referer";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    // the Apache/nginx combined format, followed by the tunnel and the duration
+    #[default]
+    Combined,
+    Json,
+    Off,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            "off" => Ok(AccessLogFormat::Off),
+            _ => Err("expected combined, json or off".to_string()),
+        }
+    }
+}
+
+/// One request as it ends up in the access log.
+#[derive(Debug, Clone)]
+pub struct AccessEntry {
+    pub remote_addr: String,
+    pub tunnel: Option<String>,
+    pub method: String,
+    pub target: String,
+    pub version: &'static str,
+    pub host: Option<String>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub status: u16,
+    // the whole request and response, heads included
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub time: DateTime<Utc>,
+    pub duration: Duration,
+    started: Instant,
+}
+
+impl AccessEntry {
+    /// Starts the clock for a request whose head was just read.
+    pub fn new(request: &RequestHead, remote_addr: &str) -> Self {
+        Self {
+            remote_addr: remote_addr.to_string(),
+            tunnel: None,
+            method: request.method.clone(),
+            target: request.target.clone(),
+            version: request.version.as_str(),
+            host: request.host().map(str::to_string),
+            user_agent: request.headers.get(USER_AGENT).map(str::to_string),
+            referer: request.headers.get(REFERER).map(str::to_string),
+            status: 0,
+            request_bytes: 0,
+            response_bytes: 0,
+            time: Utc::now(),
+            duration: Duration::ZERO,
+            started: Instant::now(),
+        }
+    }
+
+    /// Stops the clock and writes the entry, unless the access log is off.
+    pub fn finish(&mut self, format: AccessLogFormat) {
+        self.duration = self.started.elapsed();
+        if let Some(line) = self.format(format) {
+            tracing::info!(target: ACCESS_LOG_TARGET, "{line}");
+        }
+    }
+
+    fn format(&self, format: AccessLogFormat) -> Option<String> {
+        match format {
+            AccessLogFormat::Combined => Some(self.combined()),
+            AccessLogFormat::Json => Some(self.json()),
+            AccessLogFormat::Off => None,
+        }
+    }
+
+    fn combined(&self) -> String {
+        let quoted = |value: Option<&str>| match value {
+            Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => "\"-\"".to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} {} {} {} {:.3}",
+            self.remote_addr,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.target,
+            self.version,
+            self.status,
+            self.response_bytes,
+            quoted(self.referer.as_deref()),
+            quoted(self.user_agent.as_deref()),
+            quoted(self.tunnel.as_deref()),
+            self.duration.as_secs_f64(),
+        )
+    }
+
+    fn json(&self) -> String {
+        json!({
+            "time": self.time.to_rfc3339(),
+            "remote_addr": self.remote_addr,
+            "tunnel": self.tunnel,
+            "method": self.method,
+            "target": self.target,
+            "version": self.version,
+            "host": self.host,
+            "status": self.status,
+            "request_bytes": self.request_bytes,
+            "response_bytes": self.response_bytes,
+            "duration_ms": self.duration.as_secs_f64() * 1000.0,
+            "user_agent": self.user_agent,
+            "referer": self.referer,
+        })
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{DEFAULT_MAX_HEAD_SIZE, parse_request};
+
+    fn entry() -> AccessEntry {
+        let raw =
+            b"GET /a?b=1 HTTP/1.1\r\nHost: myapp.example.com\r\nUser-Agent: curl/8.0 \"x\"\r\n\r\n";
+        let (head, _) = parse_request(raw, DEFAULT_MAX_HEAD_SIZE).unwrap().unwrap();
+        let mut entry = AccessEntry::new(&head, "203.0.113.7");
+        entry.tunnel = Some("myapp".to_string());
+        entry.status = 200;
+        entry.request_bytes = 80;
+        entry.response_bytes = 512;
+        entry.time = DateTime::parse_from_rfc3339("2025-01-02T03:04:05Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        entry.duration = Duration::from_millis(1500);
+        entry
+    }
+
+    #[test]
+    fn test_combined() {
+        assert_eq!(
+            entry().combined(),
+            "203.0.113.7 - - [02/Jan/2025:03:04:05 +0000] \"GET /a?b=1 HTTP/1.1\" 200 512 \"-\" \"curl/8.0 \\\"x\\\"\" \"myapp\" 1.500"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let value: serde_json::Value = serde_json::from_str(&entry().json()).unwrap();
+        assert_eq!(value["tunnel"], "myapp");
+        assert_eq!(value["host"], "myapp.example.com");
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["response_bytes"], 512);
+        assert_eq!(value["duration_ms"], 1500.0);
+        assert_eq!(value["referer"], serde_json::Value::Null);
+        assert_eq!(value["time"], "2025-01-02T03:04:05+00:00");
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("JSON".parse(), Ok(AccessLogFormat::Json));
+        assert!("xml".parse::<AccessLogFormat>().is_err());
+        assert_eq!(entry().format(AccessLogFormat::Off), None);
+    }
+}
@@ -0,0 +1,679 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::Timeouts;
+use crate::http::{DEFAULT_MAX_HEAD_SIZE, RequestHead, parse_request};
+use crate::replay::{Overrides, ReplayError, replay};
+use crate::response::HttpResponse;
+use crate::shared::{RenameError, SharedState, TunnelInfo};
+
+const AUTHORIZATION: &str = "Authorization";
+const TUNNELS_PATH: &str = "/api/tunnels";
+const REQUESTS_PATH: &str = "/api/requests";
+const METRICS_PATH: &str = "/metrics";
+const CAPTURES: &str = "captures";
+const REPLAY: &str = "replay";
+// admin requests are small, anything bigger is refused
+const MAX_BODY_SIZE: usize = 64 * 1024;
+// a comment line on an idle event stream, so proxies keep it open and dead clients are noticed
+const STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// JSON API to look at and control the connected tunnels, every request needs the admin token.
+pub struct AdminServer {
+    listener: TcpListener,
+    shared_state: SharedState,
+    token: Arc<String>,
+    // the same limits as the public listener, for reading requests
+    timeouts: Timeouts,
+}
+
+#[derive(Deserialize)]
+struct RenameRequest {
+    name: String,
+}
+
+impl AdminServer {
+    pub async fn new(
+        addr: SocketAddr,
+        shared_state: SharedState,
+        token: String,
+        timeouts: Timeouts,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(AdminServer {
+            listener,
+            shared_state,
+            token: Arc::new(token),
+            timeouts,
+        })
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            let (socket, addr) = self.listener.accept().await?;
+            let shared_state = self.shared_state.clone();
+            let token = self.token.clone();
+            let timeouts = self.timeouts.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(socket, shared_state, &token, &timeouts).await {
+                    tracing::debug!("admin connection from {addr} failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    shared_state: SharedState,
+    token: &str,
+    timeouts: &Timeouts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buffer = Vec::new();
+    let mut buf = vec![0u8; 4096];
+    let head_timeout = Duration::from_secs(timeouts.header_read_secs);
+    let read_head = async {
+        loop {
+            if let Some(parsed) = parse_request(&buffer, DEFAULT_MAX_HEAD_SIZE)? {
+                return Ok::<_, Box<dyn std::error::Error>>(Some(parsed));
+            }
+            let n = socket.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buffer.extend_from_slice(&buf[..n]);
+        }
+    };
+    let Some((request, head_len)) = tokio::time::timeout(head_timeout, read_head)
+        .await
+        .map_err(|_| "timed out reading the request head")??
+    else {
+        return Ok(());
+    };
+
+    // nothing more is read from a client without the token
+    let body_len = request.headers.content_length().unwrap_or(0);
+    let response = if !authorized(&request, token) {
+        error(401, "Unauthorized", "missing or wrong admin token")
+    } else if body_len > MAX_BODY_SIZE {
+        error(413, "Payload Too Large", "request body too large")
+    } else {
+        let body_timeout = Duration::from_secs(timeouts.body_read_secs);
+        let mut body = buffer.split_off(head_len);
+        while body.len() < body_len {
+            let n = tokio::time::timeout(body_timeout, socket.read(&mut buf))
+                .await
+                .map_err(|_| "timed out reading the request body")??;
+            if n == 0 {
+                return Ok(());
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+        body.truncate(body_len);
+        if let Some(tunnel) = capture_stream(&request) {
+            return stream_captures(socket, &shared_state, tunnel).await;
+        }
+        handle(&request, &body, &shared_state).await
+    };
+    socket.write_all(response.to_string().as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+// hashing first keeps the comparison from leaking how much of the token matched
+fn authorized(request: &RequestHead, token: &str) -> bool {
+    let Some(presented) = request
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    Sha256::digest(presented.trim().as_bytes()) == Sha256::digest(token.as_bytes())
+}
+
+async fn handle(request: &RequestHead, body: &[u8], shared_state: &SharedState) -> HttpResponse {
+    let path = request.target.split('?').next().unwrap_or_default();
+    let path = path.trim_end_matches('/');
+    let method = request.method.as_str();
+
+    if path == METRICS_PATH {
+        if method != "GET" {
+            return method_not_allowed();
+        }
+        let text = shared_state.metrics.render(&shared_state.tunnels().await);
+        return HttpResponse::new(200, "OK", "text/plain; version=0.0.4", &text);
+    }
+
+    if path == REQUESTS_PATH {
+        if method != "GET" {
+            return method_not_allowed();
+        }
+        let requests: Vec<Value> = shared_state
+            .pending_requests()
+            .into_iter()
+            .map(|name| {
+                // stream names are `<tunnel>_<kind>-<number>`, see `shared::stream_name`
+                let tunnel = name.rsplit_once('_').map(|(tunnel, _)| tunnel.to_string());
+                json!({ "id": name, "tunnel": tunnel })
+            })
+            .collect();
+        return ok(json!({ "requests": requests }));
+    }
+
+    if path == TUNNELS_PATH {
+        if method != "GET" {
+            return method_not_allowed();
+        }
+        let tunnels: Vec<Value> = shared_state
+            .tunnels()
+            .await
+            .iter()
+            .map(tunnel_json)
+            .collect();
+        return ok(json!({ "tunnels": tunnels }));
+    }
+
+    let Some(rest) = path
+        .strip_prefix(TUNNELS_PATH)
+        .and_then(|rest| rest.strip_prefix('/'))
+    else {
+        return error(404, "Not Found", "no such endpoint");
+    };
+    match rest.split_once('/') {
+        None => match method {
+            "GET" => match shared_state
+                .tunnels()
+                .await
+                .iter()
+                .find(|info| info.name() == rest)
+            {
+                Some(info) => ok(tunnel_json(info)),
+                None => error(404, "Not Found", "no such tunnel"),
+            },
+            "DELETE" => {
+                if shared_state.disconnect_tunnel(rest).await {
+                    tracing::info!("admin disconnected tunnel [{rest}]");
+                    ok(json!({ "disconnected": rest }))
+                } else {
+                    error(404, "Not Found", "no such tunnel")
+                }
+            }
+            _ => method_not_allowed(),
+        },
+        Some((name, "rename")) => {
+            if method != "POST" {
+                return method_not_allowed();
+            }
+            let Ok(rename) = serde_json::from_slice::<RenameRequest>(body) else {
+                return error(400, "Bad Request", "expected {\"name\": \"<new name>\"}");
+            };
+            match shared_state.rename_tunnel(name, &rename.name).await {
+                Ok(info) => {
+                    tracing::info!("admin renamed tunnel [{name}] to [{}]", info.name());
+                    ok(tunnel_json(&info))
+                }
+                Err(e @ RenameError::NotFound) => error(404, "Not Found", &e.to_string()),
+                Err(e @ RenameError::InvalidName) => error(400, "Bad Request", &e.to_string()),
+                Err(e @ RenameError::Taken) => error(409, "Conflict", &e.to_string()),
+            }
+        }
+        Some((name, CAPTURES)) => {
+            if method != "GET" {
+                return method_not_allowed();
+            }
+            if !is_connected(shared_state, name).await {
+                return error(404, "Not Found", "no such tunnel");
+            }
+            let captures: Vec<Value> = shared_state
+                .captures
+                .list(name)
+                .iter()
+                .map(|capture| capture.summary_json())
+                .collect();
+            ok(json!({ "captures": captures }))
+        }
+        Some((name, rest)) => {
+            let Some(rest) = rest
+                .strip_prefix(CAPTURES)
+                .and_then(|rest| rest.strip_prefix('/'))
+            else {
+                return error(404, "Not Found", "no such endpoint");
+            };
+            // `<n>` or `<n>/replay`
+            let (id, action) = match rest.split_once('/') {
+                Some((id, action)) => (id, Some(action)),
+                None => (rest, None),
+            };
+            let Ok(id) = id.parse() else {
+                return error(404, "Not Found", "no such endpoint");
+            };
+            match (method, action) {
+                ("GET", None) | ("POST", Some(REPLAY)) => {}
+                (_, None | Some(REPLAY)) => return method_not_allowed(),
+                _ => return error(404, "Not Found", "no such endpoint"),
+            }
+            let Some(capture) = shared_state.captures.get(name, id) else {
+                return error(404, "Not Found", "no such capture");
+            };
+            if action.is_none() {
+                return ok(capture.to_json());
+            }
+            let overrides = if body.is_empty() {
+                Overrides::default()
+            } else {
+                match serde_json::from_slice(body) {
+                    Ok(overrides) => overrides,
+                    Err(e) => return error(400, "Bad Request", &format!("invalid overrides: {e}")),
+                }
+            };
+            match replay(shared_state, &capture, &overrides).await {
+                Ok(capture) => {
+                    tracing::info!("admin replayed capture {id} of [{name}]");
+                    ok(capture.to_json())
+                }
+                Err(e @ ReplayError::InvalidOverride(_)) => {
+                    error(400, "Bad Request", &e.to_string())
+                }
+                Err(e @ ReplayError::Truncated) => error(409, "Conflict", &e.to_string()),
+                Err(e @ ReplayError::TunnelGone) => {
+                    error(503, "Service Unavailable", &e.to_string())
+                }
+                Err(e @ ReplayError::Aborted) => error(502, "Bad Gateway", &e.to_string()),
+                Err(e @ ReplayError::Timeout) => error(504, "Gateway Timeout", &e.to_string()),
+            }
+        }
+    }
+}
+
+async fn is_connected(shared_state: &SharedState, name: &str) -> bool {
+    shared_state
+        .tunnels()
+        .await
+        .iter()
+        .any(|info| info.name() == name)
+}
+
+// `GET /api/tunnels/<id>/captures/stream`
+fn capture_stream(request: &RequestHead) -> Option<&str> {
+    if request.method != "GET" {
+        return None;
+    }
+    let path = request.target.split('?').next().unwrap_or_default();
+    let (name, rest) = path
+        .strip_prefix(TUNNELS_PATH)?
+        .strip_prefix('/')?
+        .split_once('/')?;
+    (rest.trim_end_matches('/') == "captures/stream").then_some(name)
+}
+
+/// Sends every new capture of `tunnel` as a server-sent event until the client goes away.
+async fn stream_captures<S: AsyncWrite + Unpin>(
+    mut socket: S,
+    shared_state: &SharedState,
+    tunnel: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut live = shared_state.captures.subscribe();
+    socket
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    socket.flush().await?;
+    let mut keep_alive = tokio::time::interval(STREAM_KEEP_ALIVE);
+    keep_alive.tick().await;
+    loop {
+        let event = select! {
+            capture = live.recv() => match capture {
+                Ok(capture) if capture.tunnel == tunnel => {
+                    format!("event: capture\ndata: {}\n\n", capture.to_json())
+                }
+                Ok(_) => continue,
+                // a slow client misses some, it is told how many
+                Err(RecvError::Lagged(missed)) => format!("event: lagged\ndata: {missed}\n\n"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+        };
+        socket.write_all(event.as_bytes()).await?;
+        socket.flush().await?;
+    }
+    Ok(())
+}
+
+fn tunnel_json(info: &Arc<TunnelInfo>) -> Value {
+    json!({
+        "id": info.name(),
+        "owner": info.owner,
+        "peer_addr": info.peer.to_string(),
+        "connected_since": info.connected_at.to_rfc3339(),
+        "bytes_received": info.bytes_received.load(Ordering::Relaxed),
+        "bytes_sent": info.bytes_sent.load(Ordering::Relaxed),
+        "in_flight_requests": info.in_flight.load(Ordering::Relaxed),
+    })
+}
+
+fn ok(value: Value) -> HttpResponse {
+    HttpResponse::new(200, "OK", "application/json", &value.to_string())
+}
+
+fn error(status_code: u16, status_text: &str, message: &str) -> HttpResponse {
+    let body = json!({ "error": message }).to_string();
+    HttpResponse::new(status_code, status_text, "application/json", &body)
+}
+
+fn method_not_allowed() -> HttpResponse {
+    error(405, "Method Not Allowed", "method not allowed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Captures;
+    use crate::custom_domain::CustomDomains;
+    use crate::shared::ResponseEvent;
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+
+    async fn call(shared_state: &SharedState, raw: &str) -> (u16, Value) {
+        let (request, head_len) = parse_request(raw.as_bytes(), DEFAULT_MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        let response = if authorized(&request, "s3cret") {
+            handle(&request, &raw.as_bytes()[head_len..], shared_state).await
+        } else {
+            error(401, "Unauthorized", "missing or wrong admin token")
+        };
+        let text = response.to_string();
+        let status = text[9..12].parse().unwrap();
+        let (_, body) = text.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    async fn state_with_tunnel(
+        name: &str,
+    ) -> (
+        SharedState,
+        mpsc::UnboundedReceiver<crate::shared::TicketRequestHttp>,
+    ) {
+        let shared_state = SharedState::new(
+            Duration::from_secs(30),
+            CustomDomains::new(&[], false, ([127, 0, 0, 1], 53).into()),
+            Captures::new(5, 1024),
+        );
+        shared_state.registry.claim(name).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let info = TunnelInfo::new(name, "acme", "127.0.0.1:5000".parse().unwrap());
+        shared_state.register_tcp_client(tx, Arc::new(info)).await;
+        (shared_state, rx)
+    }
+
+    #[tokio::test]
+    async fn test_requires_token() {
+        let (shared_state, _rx) = state_with_tunnel("myapp").await;
+        let (status, _) = call(&shared_state, "GET /api/tunnels HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, 401);
+        let (status, _) = call(
+            &shared_state,
+            "GET /api/tunnels HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, 401);
+    }
+
+    #[tokio::test]
+    async fn test_token_checked_before_body() {
+        let (shared_state, _rx) = state_with_tunnel("myapp").await;
+        let (mut client, server) = tokio::io::duplex(4096);
+        let timeouts = Timeouts {
+            header_read_secs: 1,
+            ..Timeouts::default()
+        };
+        let task = tokio::spawn(async move {
+            handle_connection(server, shared_state, "s3cret", &timeouts)
+                .await
+                .is_ok()
+        });
+
+        // a body is announced but never sent, the answer does not wait for it
+        client
+            .write_all(b"POST /api/tunnels/myapp/rename HTTP/1.1\r\nContent-Length: 60000\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = vec![0u8; 1024];
+        let n = client.read(&mut response).await.unwrap();
+        assert!(response[..n].starts_with(b"HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(task.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_head_timeout() {
+        let (shared_state, _rx) = state_with_tunnel("myapp").await;
+        let (mut client, server) = tokio::io::duplex(4096);
+        let timeouts = Timeouts {
+            header_read_secs: 1,
+            ..Timeouts::default()
+        };
+        client.write_all(b"GET /api/tunn").await.unwrap();
+        let result = handle_connection(server, shared_state, "s3cret", &timeouts).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_rename_and_disconnect() {
+        let (shared_state, mut rx) = state_with_tunnel("myapp").await;
+        let auth = "Authorization: Bearer s3cret\r\n";
+
+        let (status, body) = call(
+            &shared_state,
+            &format!("GET /api/tunnels HTTP/1.1\r\n{auth}\r\n"),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["tunnels"][0]["id"], "myapp");
+        assert_eq!(body["tunnels"][0]["peer_addr"], "127.0.0.1:5000");
+        assert_eq!(body["tunnels"][0]["in_flight_requests"], 0);
+
+        let rename = |name: &str| {
+            let body = format!("{{\"name\": \"{name}\"}}");
+            format!(
+                "POST /api/tunnels/myapp/rename HTTP/1.1\r\n{auth}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+        let (status, _) = call(&shared_state, &rename("not valid")).await;
+        assert_eq!(status, 400);
+        let (status, body) = call(&shared_state, &rename("renamed")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["id"], "renamed");
+        let (status, _) = call(&shared_state, &rename("other")).await;
+        assert_eq!(status, 404);
+
+        let (status, _) = call(
+            &shared_state,
+            &format!("DELETE /api/tunnels/renamed HTTP/1.1\r\n{auth}\r\n"),
+        )
+        .await;
+        assert_eq!(status, 200);
+        // the connection task sees its ticket channel close
+        assert!(rx.recv().await.is_none());
+        let (_, body) = call(
+            &shared_state,
+            &format!("GET /api/tunnels HTTP/1.1\r\n{auth}\r\n"),
+        )
+        .await;
+        assert_eq!(body["tunnels"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_pending_requests() {
+        let (shared_state, _rx) = state_with_tunnel("myapp").await;
+        let (tx, _rx_http) = mpsc::channel(1);
+        let _pending = shared_state.register_http_client("myapp_tx-1234".to_string(), tx);
+        let (status, body) = call(
+            &shared_state,
+            "GET /api/requests HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            body["requests"],
+            json!([{ "id": "myapp_tx-1234", "tunnel": "myapp" }])
+        );
+    }
+
+    fn record(shared_state: &SharedState, target: &str) {
+        let raw = format!("POST {target} HTTP/1.1\r\nHost: myapp.example.com\r\n\r\n");
+        let (request, _) = parse_request(raw.as_bytes(), DEFAULT_MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        let mut recorder = shared_state
+            .captures
+            .recorder("myapp", "203.0.113.7", &request)
+            .unwrap();
+        recorder.request_body(b"{\"event\": \"paid\"}");
+        recorder.response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        recorder.finish();
+    }
+
+    #[tokio::test]
+    async fn test_captures() {
+        let (shared_state, _rx) = state_with_tunnel("myapp").await;
+        let auth = "Authorization: Bearer s3cret\r\n";
+        record(&shared_state, "/first");
+        record(&shared_state, "/second");
+
+        let (status, body) = call(
+            &shared_state,
+            &format!("GET /api/tunnels/myapp/captures HTTP/1.1\r\n{auth}\r\n"),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["captures"][0]["target"], "/second");
+        assert_eq!(body["captures"][1]["status"], 200);
+        let id = body["captures"][1]["id"].as_u64().unwrap();
+
+        let (status, body) = call(
+            &shared_state,
+            &format!("GET /api/tunnels/myapp/captures/{id} HTTP/1.1\r\n{auth}\r\n"),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["request"]["body"], "{\"event\": \"paid\"}");
+        assert_eq!(body["response"]["body"], "ok");
+
+        for target in [
+            "/api/tunnels/myapp/captures/999",
+            "/api/tunnels/other/captures",
+        ] {
+            let (status, _) = call(
+                &shared_state,
+                &format!("GET {target} HTTP/1.1\r\n{auth}\r\n"),
+            )
+            .await;
+            assert_eq!(status, 404, "{target}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let (shared_state, mut rx) = state_with_tunnel("myapp").await;
+        let auth = "Authorization: Bearer s3cret\r\n";
+        record(&shared_state, "/hook");
+        let original = shared_state.captures.list("myapp")[0].id;
+
+        // stands in for the tunnel connection
+        let state = shared_state.clone();
+        tokio::spawn(async move {
+            let ticket = rx.recv().await.unwrap();
+            let request = String::from_utf8(ticket.data).unwrap();
+            assert!(request.contains("X-Retry: 1\r\n"), "{request}");
+            assert!(request.ends_with("{\"event\": \"paid\"}"), "{request}");
+            let tx_http = state.http_sender(&ticket.name).unwrap();
+            let response = b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n".to_vec();
+            tx_http.send(ResponseEvent::Data(response)).await.unwrap();
+            tx_http.send(ResponseEvent::End).await.unwrap();
+        });
+
+        let overrides = "{\"headers\": {\"X-Retry\": \"1\"}}";
+        let (status, body) = call(
+            &shared_state,
+            &format!(
+                "POST /api/tunnels/myapp/captures/{original}/replay HTTP/1.1\r\n{auth}Content-Length: {}\r\n\r\n{overrides}",
+                overrides.len()
+            ),
+        )
+        .await;
+        assert_eq!(status, 200, "{body}");
+        assert_eq!(body["status"], 202);
+        assert_eq!(body["replay_of"], original);
+        // the replay sits next to the original
+        let captures = shared_state.captures.list("myapp");
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures[1].id, original);
+        assert!(shared_state.pending_requests().is_empty());
+
+        let (status, _) = call(
+            &shared_state,
+            &format!("GET /api/tunnels/myapp/captures/{original}/replay HTTP/1.1\r\n{auth}\r\n"),
+        )
+        .await;
+        assert_eq!(status, 405);
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream() {
+        let (shared_state, _rx) = state_with_tunnel("myapp").await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let state = shared_state.clone();
+        tokio::spawn(async move {
+            let _ = stream_captures(socket, &state, "myapp").await;
+        });
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !received.ends_with(b"\r\n\r\n") {
+            let n = client.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert!(String::from_utf8_lossy(&received).contains("text/event-stream"));
+
+        record(&shared_state, "/hook");
+        received.clear();
+        while !received.ends_with(b"\n\n") {
+            let n = client.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        let event = String::from_utf8(received).unwrap();
+        let data = event.strip_prefix("event: capture\ndata: ").unwrap();
+        let value: Value = serde_json::from_str(data.trim_end()).unwrap();
+        assert_eq!(value["target"], "/hook");
+    }
+
+    #[test]
+    fn test_capture_stream_path() {
+        let head = |raw: &str| {
+            parse_request(raw.as_bytes(), DEFAULT_MAX_HEAD_SIZE)
+                .unwrap()
+                .unwrap()
+                .0
+        };
+        let request = head("GET /api/tunnels/myapp/captures/stream HTTP/1.1\r\n\r\n");
+        assert_eq!(capture_stream(&request), Some("myapp"));
+        let request = head("GET /api/tunnels/myapp/captures/3 HTTP/1.1\r\n\r\n");
+        assert_eq!(capture_stream(&request), None);
+    }
+}
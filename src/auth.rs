@@ -0,0 +1,246 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Who is behind a tunnel, as far as the authenticator can tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    Expired,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "missing auth token"),
+            AuthError::InvalidToken => write!(f, "invalid auth token"),
+            AuthError::Expired => write!(f, "auth token expired"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, token: Option<&str>) -> Result<Identity, AuthError>;
+}
+
+/// Accepts every client, used when no auth backend is configured.
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn authenticate(&self, _token: Option<&str>) -> Result<Identity, AuthError> {
+        Ok(Identity {
+            name: "anonymous".to_string(),
+        })
+    }
+}
+
+/// Tokens listed in a file, one `<token> [name]` per line, `#` starts a comment.
+pub struct StaticTokens {
+    tokens: HashMap<String, String>,
+}
+
+impl StaticTokens {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read tokens file {path}: {e}"))?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let mut tokens = HashMap::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut parts = line.split_whitespace();
+            if let Some(token) = parts.next() {
+                let name = parts.next().unwrap_or(token).to_string();
+                tokens.insert(token.to_string(), name);
+            }
+        }
+        Self { tokens }
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, token: Option<&str>) -> Result<Identity, AuthError> {
+        let token = token.ok_or(AuthError::MissingToken)?;
+        self.tokens
+            .get(token)
+            .map(|name| Identity { name: name.clone() })
+            .ok_or(AuthError::InvalidToken)
+    }
+}
+
+/// Self-contained tokens `<name>.<expires unix seconds, 0 = never>.<hex hmac-sha256 of "name.expires">`.
+pub struct HmacTokens {
+    secret: Vec<u8>,
+}
+
+impl HmacTokens {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("hmac accepts keys of any length")
+    }
+
+    #[cfg(test)]
+    pub fn sign(&self, name: &str, expires: u64) -> String {
+        let mut mac = self.mac();
+        mac.update(format!("{name}.{expires}").as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        format!("{name}.{expires}.{signature}")
+    }
+}
+
+impl Authenticator for HmacTokens {
+    fn authenticate(&self, token: Option<&str>) -> Result<Identity, AuthError> {
+        let token = token.ok_or(AuthError::MissingToken)?;
+        let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::InvalidToken)?;
+        let (name, expires) = payload.rsplit_once('.').ok_or(AuthError::InvalidToken)?;
+        let expires: u64 = expires.parse().map_err(|_| AuthError::InvalidToken)?;
+        let signature = hex::decode(signature).map_err(|_| AuthError::InvalidToken)?;
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        if expires != 0 && expires < chrono::Utc::now().timestamp() as u64 {
+            return Err(AuthError::Expired);
+        }
+        Ok(Identity {
+            name: name.to_string(),
+        })
+    }
+}
+
+/// Tries each backend in order and accepts the first match.
+pub struct AnyOf {
+    backends: Vec<Box<dyn Authenticator>>,
+}
+
+impl Authenticator for AnyOf {
+    fn authenticate(&self, token: Option<&str>) -> Result<Identity, AuthError> {
+        let mut last_error = AuthError::MissingToken;
+        for backend in &self.backends {
+            match backend.authenticate(token) {
+                Ok(identity) => return Ok(identity),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+pub fn build_authenticator(
+    tokens_file: Option<&str>,
+    hmac_secret: Option<&str>,
+) -> Result<Arc<dyn Authenticator>, Box<dyn std::error::Error>> {
+    let mut backends: Vec<Box<dyn Authenticator>> = Vec::new();
+    if let Some(path) = tokens_file {
+        backends.push(Box::new(StaticTokens::load(path)?));
+    }
+    if let Some(secret) = hmac_secret {
+        backends.push(Box::new(HmacTokens::new(secret)));
+    }
+
+    if backends.is_empty() {
+        tracing::warn!("no auth backend configured, any client can register a subdomain");
+        return Ok(Arc::new(NoAuth));
+    }
+    Ok(Arc::new(AnyOf { backends }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_tokens() {
+        let tokens = StaticTokens::parse("# team tokens\nabc123 alice\n\nxyz789   # no name\n");
+        assert_eq!(
+            tokens.authenticate(Some("abc123")).unwrap().name,
+            "alice".to_string()
+        );
+        assert_eq!(
+            tokens.authenticate(Some("xyz789")).unwrap().name,
+            "xyz789".to_string()
+        );
+        assert_eq!(
+            tokens.authenticate(Some("nope")),
+            Err(AuthError::InvalidToken)
+        );
+        assert_eq!(tokens.authenticate(None), Err(AuthError::MissingToken));
+    }
+
+    #[test]
+    fn test_hmac_token_valid() {
+        let auth = HmacTokens::new("secret");
+        let token = auth.sign("alice", 0);
+        assert_eq!(auth.authenticate(Some(&token)).unwrap().name, "alice");
+    }
+
+    #[test]
+    fn test_hmac_token_wrong_secret() {
+        let token = HmacTokens::new("other").sign("alice", 0);
+        assert_eq!(
+            HmacTokens::new("secret").authenticate(Some(&token)),
+            Err(AuthError::InvalidToken)
+        );
+    }
+
+    #[test]
+    fn test_hmac_token_tampered_name() {
+        let auth = HmacTokens::new("secret");
+        let token = auth.sign("alice", 0).replacen("alice", "mallory", 1);
+        assert_eq!(
+            auth.authenticate(Some(&token)),
+            Err(AuthError::InvalidToken)
+        );
+    }
+
+    #[test]
+    fn test_hmac_token_expired() {
+        let auth = HmacTokens::new("secret");
+        let token = auth.sign("alice", 1);
+        assert_eq!(auth.authenticate(Some(&token)), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn test_hmac_token_garbage() {
+        let auth = HmacTokens::new("secret");
+        assert_eq!(
+            auth.authenticate(Some("not-a-token")),
+            Err(AuthError::InvalidToken)
+        );
+    }
+
+    #[test]
+    fn test_any_of() {
+        let auth = AnyOf {
+            backends: vec![
+                Box::new(StaticTokens::parse("abc123 alice")),
+                Box::new(HmacTokens::new("secret")),
+            ],
+        };
+        let token = HmacTokens::new("secret").sign("bob", 0);
+        assert_eq!(auth.authenticate(Some("abc123")).unwrap().name, "alice");
+        assert_eq!(auth.authenticate(Some(&token)).unwrap().name, "bob");
+        assert!(auth.authenticate(Some("nope")).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+use crate::http::{Headers, RequestHead, ResponseHead};
+
+const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+
+/// How the end of a message body is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    None,
+    ContentLength(usize),
+    Chunked,
+    // only for responses: the body ends when the upstream closes the connection
+    UntilClose,
+}
+
+impl BodyFraming {
+    pub fn for_request(head: &RequestHead) -> Self {
+        if is_chunked(&head.headers) {
+            BodyFraming::Chunked
+        } else {
+            match head.headers.content_length() {
+                Some(length) => BodyFraming::ContentLength(length),
+                None => BodyFraming::None,
+            }
+        }
+    }
+
+    pub fn for_response(head: &ResponseHead, head_request: bool) -> Self {
+        if head.is_switching_protocols() {
+            // switching protocols, whatever follows is raw bytes
+            return BodyFraming::UntilClose;
+        }
+        let status = head.status;
+        if head_request || (100..200).contains(&status) || status == 204 || status == 304 {
+            return BodyFraming::None;
+        }
+        if is_chunked(&head.headers) {
+            BodyFraming::Chunked
+        } else {
+            match head.headers.content_length() {
+                Some(length) => BodyFraming::ContentLength(length),
+                None => BodyFraming::UntilClose,
+            }
+        }
+    }
+}
+
+// longest chunk-size or trailer line accepted, extensions included
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Where a chunked body is between reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    // hex size line, up to and including its CRLF
+    Size,
+    // payload bytes of the current chunk
+    Data,
+    // CRLF closing a chunk
+    DataEnd,
+    // trailer lines after the last chunk, ended by an empty line
+    Trailer,
+}
+
+/// Follows a body as it streams through, so the relay knows where the message ends.
+pub struct BodyTracker {
+    framing: BodyFraming,
+    remaining: usize,
+    chunk_state: ChunkState,
+    // partial chunk-size or trailer line, it can be split across reads
+    line: Vec<u8>,
+    complete: bool,
+    invalid: bool,
+}
+
+impl BodyTracker {
+    pub fn new(framing: BodyFraming) -> Self {
+        let (remaining, complete) = match framing {
+            BodyFraming::None => (0, true),
+            BodyFraming::ContentLength(length) => (length, length == 0),
+            BodyFraming::Chunked | BodyFraming::UntilClose => (0, false),
+        };
+        Self {
+            framing,
+            remaining,
+            chunk_state: ChunkState::Size,
+            line: Vec::new(),
+            complete,
+            invalid: false,
+        }
+    }
+
+    pub fn framing(&self) -> BodyFraming {
+        self.framing
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The chunked encoding could not be parsed, the connection can not be reused.
+    pub fn is_invalid(&self) -> bool {
+        self.invalid
+    }
+
+    /// Consumes body bytes and returns how many of them belong to this body,
+    /// anything after that is the start of the next message.
+    pub fn feed(&mut self, bytes: &[u8]) -> usize {
+        if self.complete {
+            return 0;
+        }
+        match self.framing {
+            BodyFraming::None => 0,
+            BodyFraming::ContentLength(_) => {
+                let used = bytes.len().min(self.remaining);
+                self.remaining -= used;
+                self.complete = self.remaining == 0;
+                used
+            }
+            BodyFraming::Chunked => self.feed_chunked(bytes),
+            BodyFraming::UntilClose => bytes.len(),
+        }
+    }
+
+    fn feed_chunked(&mut self, bytes: &[u8]) -> usize {
+        let mut pos = 0;
+        while pos < bytes.len() && !self.complete {
+            if self.invalid {
+                return bytes.len();
+            }
+            match self.chunk_state {
+                ChunkState::DataEnd => {
+                    match (self.line.is_empty(), bytes[pos]) {
+                        (true, b'\r') => self.line.push(b'\r'),
+                        (_, b'\n') => {
+                            self.line.clear();
+                            self.chunk_state = ChunkState::Size;
+                        }
+                        _ => self.fail(),
+                    }
+                    pos += 1;
+                }
+                ChunkState::Size | ChunkState::Trailer => {
+                    let Some(line) = self.take_line(bytes, &mut pos) else {
+                        break;
+                    };
+                    self.end_line(&line);
+                }
+                ChunkState::Data => {
+                    let used = (bytes.len() - pos).min(self.remaining);
+                    pos += used;
+                    self.remaining -= used;
+                    if self.remaining == 0 {
+                        self.chunk_state = ChunkState::DataEnd;
+                    }
+                }
+            }
+        }
+        pos
+    }
+
+    /// Collects bytes up to the next LF, returns the line without its line ending once it is whole.
+    fn take_line(&mut self, bytes: &[u8], pos: &mut usize) -> Option<Vec<u8>> {
+        match bytes[*pos..].iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                self.line.extend_from_slice(&bytes[*pos..*pos + end]);
+                *pos += end + 1;
+                let mut line = std::mem::take(&mut self.line);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                Some(line)
+            }
+            None => {
+                self.line.extend_from_slice(&bytes[*pos..]);
+                *pos = bytes.len();
+                if self.line.len() > MAX_CHUNK_LINE {
+                    self.fail();
+                }
+                None
+            }
+        }
+    }
+
+    fn end_line(&mut self, line: &[u8]) {
+        match self.chunk_state {
+            ChunkState::Size => match parse_chunk_size(line) {
+                Some(0) => self.chunk_state = ChunkState::Trailer,
+                Some(size) => {
+                    self.remaining = size;
+                    self.chunk_state = ChunkState::Data;
+                }
+                None => self.fail(),
+            },
+            ChunkState::Trailer if line.is_empty() => self.complete = true,
+            _ => {}
+        }
+    }
+
+    // nothing past a broken chunk can be trusted, so the rest of the stream is passed on as is
+    fn fail(&mut self) {
+        self.invalid = true;
+        self.framing = BodyFraming::UntilClose;
+    }
+}
+
+/// Parses a chunk-size line, ignoring any `;name=value` extensions.
+fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok()
+}
+
+/// Chunked wins over `Content-Length` when a message carries both.
+fn is_chunked(headers: &Headers) -> bool {
+    headers.has_token(TRANSFER_ENCODING, "chunked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{DEFAULT_MAX_HEAD_SIZE, parse_request, parse_response};
+
+    fn request_framing(text: &str) -> BodyFraming {
+        let (head, _) = parse_request(format!("{text}\r\n").as_bytes(), DEFAULT_MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        BodyFraming::for_request(&head)
+    }
+
+    fn response_framing(text: &str, head_request: bool) -> BodyFraming {
+        let (head, _) = parse_response(format!("{text}\r\n").as_bytes(), DEFAULT_MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        BodyFraming::for_response(&head, head_request)
+    }
+
+    #[test]
+    fn test_request_framing() {
+        assert_eq!(
+            request_framing("POST / HTTP/1.1\r\nContent-Length: 10\r\n"),
+            BodyFraming::ContentLength(10)
+        );
+        assert_eq!(
+            request_framing("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n"),
+            BodyFraming::Chunked
+        );
+        assert_eq!(
+            request_framing("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, Chunked\r\n"),
+            BodyFraming::Chunked
+        );
+        assert_eq!(
+            request_framing("GET / HTTP/1.1\r\nHost: a.example.com\r\n"),
+            BodyFraming::None
+        );
+    }
+
+    #[test]
+    fn test_response_framing() {
+        assert_eq!(
+            response_framing("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n", false),
+            BodyFraming::ContentLength(5)
+        );
+        assert_eq!(
+            response_framing("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n", true),
+            BodyFraming::None
+        );
+        assert_eq!(
+            response_framing("HTTP/1.1 304 Not Modified\r\nETag: x\r\n", false),
+            BodyFraming::None
+        );
+        assert_eq!(
+            response_framing("HTTP/1.1 200 OK\r\nServer: x\r\n", false),
+            BodyFraming::UntilClose
+        );
+        assert_eq!(
+            response_framing("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n", false),
+            BodyFraming::Chunked
+        );
+    }
+
+    #[test]
+    fn test_switching_protocols_framing() {
+        assert_eq!(
+            response_framing(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n",
+                false
+            ),
+            BodyFraming::UntilClose
+        );
+    }
+
+    #[test]
+    fn test_content_length_tracker() {
+        let mut tracker = BodyTracker::new(BodyFraming::ContentLength(5));
+        assert_eq!(tracker.feed(b"hel"), 3);
+        assert!(!tracker.is_complete());
+        assert_eq!(tracker.feed(b"loGET /"), 2);
+        assert!(tracker.is_complete());
+        assert_eq!(tracker.feed(b"more"), 0);
+    }
+
+    #[test]
+    fn test_empty_body_is_complete() {
+        assert!(BodyTracker::new(BodyFraming::None).is_complete());
+        assert!(BodyTracker::new(BodyFraming::ContentLength(0)).is_complete());
+        assert!(!BodyTracker::new(BodyFraming::UntilClose).is_complete());
+    }
+
+    #[test]
+    fn test_chunked_tracker_split_terminator() {
+        let mut tracker = BodyTracker::new(BodyFraming::Chunked);
+        assert_eq!(tracker.feed(b"5\r\nhello\r\n0\r"), 12);
+        assert!(!tracker.is_complete());
+        assert_eq!(tracker.feed(b"\n\r\nGET"), 3);
+        assert!(tracker.is_complete());
+    }
+
+    #[test]
+    fn test_chunked_terminator_inside_body() {
+        let mut tracker = BodyTracker::new(BodyFraming::Chunked);
+        let body = b"a\r\nab0\r\n\r\ncde\r\n0\r\n\r\n";
+        assert_eq!(tracker.feed(body), body.len());
+        assert!(tracker.is_complete());
+    }
+
+    #[test]
+    fn test_chunked_extensions_and_trailers() {
+        let mut tracker = BodyTracker::new(BodyFraming::Chunked);
+        let body = b"4;name=value\r\nwiki\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\n";
+        assert_eq!(
+            tracker.feed(&[body.as_slice(), b"HTTP/1.1"].concat()),
+            body.len()
+        );
+        assert!(tracker.is_complete());
+    }
+
+    #[test]
+    fn test_chunked_byte_by_byte() {
+        let body = b"3\r\nabc\r\n10\r\n0123456789abcdef\r\n0\r\n\r\n";
+        let mut tracker = BodyTracker::new(BodyFraming::Chunked);
+        for (i, byte) in body.iter().enumerate() {
+            assert!(!tracker.is_complete(), "complete early at byte {i}");
+            assert_eq!(tracker.feed(&[*byte]), 1);
+        }
+        assert!(tracker.is_complete());
+        assert!(!tracker.is_invalid());
+    }
+
+    #[test]
+    fn test_chunked_invalid_size() {
+        let mut tracker = BodyTracker::new(BodyFraming::Chunked);
+        assert_eq!(tracker.feed(b"zz\r\nhello\r\n"), 11);
+        assert!(tracker.is_invalid());
+        assert!(!tracker.is_complete());
+        assert_eq!(tracker.framing(), BodyFraming::UntilClose);
+
+        let mut tracker = BodyTracker::new(BodyFraming::Chunked);
+        tracker.feed(b"2\r\nabXY");
+        assert!(tracker.is_invalid());
+    }
+
+    #[test]
+    fn test_parse_chunk_size() {
+        assert_eq!(parse_chunk_size(b"1A"), Some(26));
+        assert_eq!(parse_chunk_size(b"ff ; ext"), Some(255));
+        assert_eq!(parse_chunk_size(b""), None);
+        assert_eq!(parse_chunk_size(b"+5"), None);
+        assert_eq!(parse_chunk_size(b"ffffffffffffffffffff"), None);
+    }
+
+    #[test]
+    fn test_until_close_tracker() {
+        let mut tracker = BodyTracker::new(BodyFraming::UntilClose);
+        assert_eq!(tracker.feed(b"anything"), 8);
+        assert!(!tracker.is_complete());
+    }
+}
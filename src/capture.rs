@@ -0,0 +1,373 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use crate::http::{Headers, RequestHead, ResponseHead, parse_response};
+
+// captures not yet picked up by a slow subscriber, older ones are skipped for it
+const LIVE_CAPACITY: usize = 64;
+
+/// A body as far as it was kept, the rest is dropped once `max_body_size` is reached.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedBody {
+    pub bytes: Vec<u8>,
+    pub truncated: bool,
+}
+
+impl CapturedBody {
+    fn extend(&mut self, bytes: &[u8], limit: usize) {
+        let room = limit.saturating_sub(self.bytes.len());
+        if bytes.len() > room {
+            self.truncated = true;
+        }
+        self.bytes
+            .extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+}
+
+/// One request through a tunnel and the response the browser got.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub id: u64,
+    pub tunnel: String,
+    pub remote_addr: String,
+    pub time: DateTime<Utc>,
+    pub duration: Duration,
+    pub request: RequestHead,
+    // as sent, chunked encoding included
+    pub request_body: CapturedBody,
+    // `None` when no response head came back
+    pub response: Option<(ResponseHead, CapturedBody)>,
+    // set on a request sent again from the admin API, the capture it came from
+    pub replay_of: Option<u64>,
+}
+
+impl Capture {
+    /// What the list endpoints show, without headers and bodies.
+    pub fn summary_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "tunnel": self.tunnel,
+            "remote_addr": self.remote_addr,
+            "time": self.time.to_rfc3339(),
+            "duration_ms": self.duration.as_secs_f64() * 1000.0,
+            "method": self.request.method,
+            "target": self.request.target,
+            "status": self.response.as_ref().map(|(head, _)| head.status),
+            "replay_of": self.replay_of,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = self.summary_json();
+        let mut request = json!({
+            "method": self.request.method,
+            "target": self.request.target,
+            "version": self.request.version.as_str(),
+            "headers": headers_json(&self.request.headers),
+        });
+        add_body(&mut request, &self.request_body);
+        value["request"] = request;
+        value["response"] = match &self.response {
+            Some((head, body)) => {
+                let mut response = json!({
+                    "status": head.status,
+                    "reason": head.reason,
+                    "version": head.version.as_str(),
+                    "headers": headers_json(&head.headers),
+                });
+                add_body(&mut response, body);
+                response
+            }
+            None => Value::Null,
+        };
+        value
+    }
+}
+
+// pairs keep the order and repeated names
+fn headers_json(headers: &Headers) -> Value {
+    headers
+        .iter()
+        .map(|(name, value)| json!([name, value]))
+        .collect()
+}
+
+// text stays readable, anything else is base64
+fn add_body(value: &mut Value, body: &CapturedBody) {
+    match std::str::from_utf8(&body.bytes) {
+        Ok(text) => {
+            value["body"] = json!(text);
+            value["body_encoding"] = json!("utf8");
+        }
+        Err(_) => {
+            value["body"] = json!(BASE64.encode(&body.bytes));
+            value["body_encoding"] = json!("base64");
+        }
+    }
+    value["body_truncated"] = json!(body.truncated);
+}
+
+/// The last requests of every connected tunnel, kept in memory for the admin API.
+#[derive(Clone)]
+pub struct Captures {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    per_tunnel: usize,
+    max_body_size: usize,
+    next_id: AtomicU64,
+    // only tunnels that are connected have an entry
+    tunnels: Mutex<HashMap<String, VecDeque<Arc<Capture>>>>,
+    live: broadcast::Sender<Arc<Capture>>,
+}
+
+impl Captures {
+    /// Keeps `per_tunnel` requests per tunnel, none at all when it is 0.
+    pub fn new(per_tunnel: usize, max_body_size: usize) -> Self {
+        let (live, _) = broadcast::channel(LIVE_CAPACITY);
+        Self {
+            inner: Arc::new(Inner {
+                per_tunnel,
+                max_body_size,
+                next_id: AtomicU64::new(1),
+                tunnels: Mutex::new(HashMap::new()),
+                live,
+            }),
+        }
+    }
+
+    /// Starts recording a request, `None` when capturing is off.
+    pub fn recorder(
+        &self,
+        tunnel: &str,
+        remote_addr: &str,
+        request: &RequestHead,
+    ) -> Option<Recorder> {
+        (self.inner.per_tunnel > 0).then(|| Recorder {
+            captures: self.clone(),
+            tunnel: tunnel.to_string(),
+            remote_addr: remote_addr.to_string(),
+            time: Utc::now(),
+            started: Instant::now(),
+            request: request.clone(),
+            request_body: CapturedBody::default(),
+            response: None,
+            replay_of: None,
+        })
+    }
+
+    pub fn open(&self, tunnel: &str) {
+        if self.inner.per_tunnel > 0 {
+            let mut tunnels = self.inner.tunnels.lock().unwrap();
+            tunnels.entry(tunnel.to_string()).or_default();
+        }
+    }
+
+    /// Forgets a tunnel that went away, along with its requests.
+    pub fn close(&self, tunnel: &str) {
+        self.inner.tunnels.lock().unwrap().remove(tunnel);
+    }
+
+    pub fn rename(&self, tunnel: &str, new_name: &str) {
+        let mut tunnels = self.inner.tunnels.lock().unwrap();
+        if let Some(captures) = tunnels.remove(tunnel) {
+            tunnels.insert(new_name.to_string(), captures);
+        }
+    }
+
+    /// The requests kept for `tunnel`, newest first.
+    pub fn list(&self, tunnel: &str) -> Vec<Arc<Capture>> {
+        let tunnels = self.inner.tunnels.lock().unwrap();
+        tunnels
+            .get(tunnel)
+            .map(|captures| captures.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, tunnel: &str, id: u64) -> Option<Arc<Capture>> {
+        let tunnels = self.inner.tunnels.lock().unwrap();
+        tunnels
+            .get(tunnel)?
+            .iter()
+            .find(|capture| capture.id == id)
+            .cloned()
+    }
+
+    /// Every capture from now on, of all tunnels.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Capture>> {
+        self.inner.live.subscribe()
+    }
+
+    fn push(&self, mut capture: Capture) -> Option<Arc<Capture>> {
+        let mut tunnels = self.inner.tunnels.lock().unwrap();
+        // the tunnel was disconnected while the request was running
+        let captures = tunnels.get_mut(&capture.tunnel)?;
+        capture.id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let capture = Arc::new(capture);
+        if captures.len() == self.inner.per_tunnel {
+            captures.pop_front();
+        }
+        captures.push_back(capture.clone());
+        // nobody listening is fine
+        let _ = self.inner.live.send(capture.clone());
+        Some(capture)
+    }
+}
+
+/// Collects one request while it is proxied, see [`Captures::recorder`].
+pub struct Recorder {
+    captures: Captures,
+    tunnel: String,
+    remote_addr: String,
+    time: DateTime<Utc>,
+    started: Instant,
+    request: RequestHead,
+    request_body: CapturedBody,
+    response: Option<(ResponseHead, CapturedBody)>,
+    replay_of: Option<u64>,
+}
+
+impl Recorder {
+    pub fn replay_of(&mut self, id: u64) {
+        self.replay_of = Some(id);
+    }
+
+    pub fn request_body(&mut self, bytes: &[u8]) {
+        let limit = self.captures.inner.max_body_size;
+        self.request_body.extend(bytes, limit);
+    }
+
+    /// Bytes written to the browser, the first ones hold the response head.
+    pub fn response(&mut self, bytes: &[u8]) {
+        let limit = self.captures.inner.max_body_size;
+        match &mut self.response {
+            Some((_, body)) => body.extend(bytes, limit),
+            None => {
+                // interim heads such as `100 Continue` are not kept
+                if let Ok(Some((head, head_len))) = parse_response(bytes, bytes.len())
+                    && !head.is_interim()
+                {
+                    let mut body = CapturedBody::default();
+                    body.extend(&bytes[head_len..], limit);
+                    self.response = Some((head, body));
+                }
+            }
+        }
+    }
+
+    /// Keeps the capture, `None` if its tunnel is gone.
+    pub fn finish(self) -> Option<Arc<Capture>> {
+        let capture = Capture {
+            id: 0,
+            tunnel: self.tunnel,
+            remote_addr: self.remote_addr,
+            time: self.time,
+            duration: self.started.elapsed(),
+            request: self.request,
+            request_body: self.request_body,
+            response: self.response,
+            replay_of: self.replay_of,
+        };
+        self.captures.push(capture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{DEFAULT_MAX_HEAD_SIZE, parse_request};
+
+    fn request() -> RequestHead {
+        let raw = b"POST /hook HTTP/1.1\r\nHost: myapp.example.com\r\nX-Sig: a\r\nX-Sig: b\r\n\r\n";
+        parse_request(raw, DEFAULT_MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap()
+            .0
+    }
+
+    fn record(captures: &Captures, body: &[u8], response: &[&[u8]]) {
+        let mut recorder = captures
+            .recorder("myapp", "203.0.113.7", &request())
+            .unwrap();
+        recorder.request_body(body);
+        for bytes in response {
+            recorder.response(bytes);
+        }
+        recorder.finish();
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let captures = Captures::new(2, 1024);
+        // nothing is kept for a tunnel that is not connected
+        record(&captures, b"", &[]);
+        assert!(captures.list("myapp").is_empty());
+
+        captures.open("myapp");
+        for _ in 0..3 {
+            record(&captures, b"", &[]);
+        }
+        let ids: Vec<u64> = captures.list("myapp").iter().map(|c| c.id).collect();
+        assert_eq!(ids, [3, 2]);
+        assert!(captures.get("myapp", 1).is_none());
+
+        captures.rename("myapp", "renamed");
+        assert!(captures.get("renamed", 3).is_some());
+        captures.close("renamed");
+        assert!(captures.list("renamed").is_empty());
+    }
+
+    #[test]
+    fn test_bodies_are_truncated() {
+        let captures = Captures::new(5, 4);
+        captures.open("myapp");
+        record(
+            &captures,
+            b"abcdef",
+            &[
+                b"HTTP/1.1 100 Continue\r\n\r\n",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n\xff\xfe",
+                b"xyz",
+            ],
+        );
+        let capture = &captures.list("myapp")[0];
+        assert_eq!(capture.request_body.bytes, b"abcd");
+        assert!(capture.request_body.truncated);
+        let (head, body) = capture.response.as_ref().unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(body.bytes, b"\xff\xfexy");
+
+        let value = capture.to_json();
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["request"]["headers"][1], json!(["X-Sig", "a"]));
+        assert_eq!(value["request"]["body"], "abcd");
+        assert_eq!(value["request"]["body_truncated"], true);
+        assert_eq!(value["response"]["body_encoding"], "base64");
+        assert_eq!(value["response"]["body"], BASE64.encode(b"\xff\xfexy"));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let captures = Captures::new(5, 1024);
+        captures.open("myapp");
+        let mut live = captures.subscribe();
+        record(&captures, b"", &[b"HTTP/1.1 204 No Content\r\n\r\n"]);
+        let capture = live.recv().await.unwrap();
+        assert_eq!(capture.summary_json()["status"], 204);
+        assert_eq!(capture.tunnel, "myapp");
+    }
+
+    #[test]
+    fn test_off() {
+        let captures = Captures::new(0, 1024);
+        assert!(captures.recorder("myapp", "::1", &request()).is_none());
+    }
+}
@@ -0,0 +1,471 @@
+use std::fmt;
+use std::io;
+
+use crate::frame::{Frame, FrameType};
+
+// header layout: [version: u8][type: u8][flags: u8][stream id: u32 BE][payload length: u32 BE]
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const FRAME_HEADER_LEN: usize = 11;
+pub const MAX_FRAME_PAYLOAD: usize = 1024 * 1024;
+
+// handshake / ack fields are encoded as [tag: u8][length: u16 BE][value]
+const TAG_CLIENT_VERSION: u8 = 1;
+const TAG_SUBDOMAIN: u8 = 2;
+const TAG_CLIENT_ID: u8 = 3;
+const TAG_TOKEN: u8 = 4;
+const TAG_RESUME_TOKEN: u8 = 5;
+const TAG_CUSTOM_DOMAIN: u8 = 6;
+// "<domain> <token>", the token the tunnel has to serve before the domain goes live
+const TAG_DOMAIN_CHALLENGE: u8 = 7;
+// u16 BE, in the handshake the wanted public port (0 for any), in the ack the one given
+const TAG_TCP_PORT: u8 = 8;
+// same as `TAG_TCP_PORT` for a UDP tunnel
+const TAG_UDP_PORT: u8 = 9;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    UnsupportedVersion(u8),
+    UnknownFrameType(u8),
+    PayloadTooLarge(usize),
+    Truncated,
+    InvalidUtf8,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "io error: {e}"),
+            CodecError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+            CodecError::UnknownFrameType(t) => write!(f, "unknown frame type {t}"),
+            CodecError::PayloadTooLarge(len) => write!(f, "frame payload too large: {len}"),
+            CodecError::Truncated => write!(f, "truncated payload"),
+            CodecError::InvalidUtf8 => write!(f, "invalid utf-8 in payload"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+/// Error codes sent to the client in an `Error` frame before the server drops the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    ClientVersionTooOld = 1,
+    UnsupportedProtocol = 2,
+    MalformedHandshake = 3,
+    Unauthorized = 4,
+    SubdomainReserved = 5,
+    SubdomainInUse = 6,
+    DomainUnavailable = 7,
+    TcpPortUnavailable = 8,
+    UdpPortUnavailable = 9,
+    InvalidSubdomain = 10,
+    HandshakeTimeout = 11,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::ClientVersionTooOld => "request_higher_version",
+            ErrorCode::UnsupportedProtocol => "unsupported_protocol",
+            ErrorCode::MalformedHandshake => "malformed_handshake",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::SubdomainReserved => "subdomain_reserved",
+            ErrorCode::SubdomainInUse => "subdomain_in_use",
+            ErrorCode::DomainUnavailable => "domain_unavailable",
+            ErrorCode::TcpPortUnavailable => "tcp_port_unavailable",
+            ErrorCode::UdpPortUnavailable => "udp_port_unavailable",
+            ErrorCode::InvalidSubdomain => "invalid_subdomain",
+            ErrorCode::HandshakeTimeout => "handshake_timeout",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ERR{:03}:{}", *self as u16, self.as_str())
+    }
+}
+
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub length: usize,
+}
+
+pub fn encode_frame(frame: &Frame) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + frame.payload.len());
+    buf.push(PROTOCOL_VERSION);
+    buf.push(frame.frame_type as u8);
+    buf.push(frame.flags);
+    buf.extend_from_slice(&frame.stream_id.to_be_bytes());
+    buf.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&frame.payload);
+    buf
+}
+
+pub fn decode_header(header: &[u8; FRAME_HEADER_LEN]) -> Result<FrameHeader, CodecError> {
+    if header[0] != PROTOCOL_VERSION {
+        return Err(CodecError::UnsupportedVersion(header[0]));
+    }
+    let frame_type =
+        FrameType::from_u8(header[1]).ok_or(CodecError::UnknownFrameType(header[1]))?;
+    let stream_id = u32::from_be_bytes([header[3], header[4], header[5], header[6]]);
+    let length = u32::from_be_bytes([header[7], header[8], header[9], header[10]]) as usize;
+    if length > MAX_FRAME_PAYLOAD {
+        return Err(CodecError::PayloadTooLarge(length));
+    }
+    Ok(FrameHeader {
+        frame_type,
+        flags: header[2],
+        stream_id,
+        length,
+    })
+}
+
+/// Decodes one frame from the front of `buf`, `Ok(None)` means more bytes are needed.
+/// On success also returns how many bytes were consumed.
+pub fn decode_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, CodecError> {
+    let Some(header) = buf.first_chunk::<FRAME_HEADER_LEN>() else {
+        return Ok(None);
+    };
+    let header = decode_header(header)?;
+    let end = FRAME_HEADER_LEN + header.length;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    let frame = Frame {
+        frame_type: header.frame_type,
+        flags: header.flags,
+        stream_id: header.stream_id,
+        payload: buf[FRAME_HEADER_LEN..end].to_vec(),
+    };
+    Ok(Some((frame, end)))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Handshake {
+    pub client_version: String,
+    pub subdomain: Option<String>,
+    pub token: Option<String>,
+    pub resume_token: Option<String>,
+    pub custom_domains: Vec<String>,
+    // asks for a raw TCP tunnel
+    pub tcp_port: Option<u16>,
+    // asks for a UDP tunnel
+    pub udp_port: Option<u16>,
+}
+
+impl Handshake {
+    // the client side of the handshake, the server only decodes
+    #[cfg(test)]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_field(&mut buf, TAG_CLIENT_VERSION, self.client_version.as_bytes());
+        if let Some(subdomain) = &self.subdomain {
+            put_field(&mut buf, TAG_SUBDOMAIN, subdomain.as_bytes());
+        }
+        if let Some(token) = &self.token {
+            put_field(&mut buf, TAG_TOKEN, token.as_bytes());
+        }
+        if let Some(resume_token) = &self.resume_token {
+            put_field(&mut buf, TAG_RESUME_TOKEN, resume_token.as_bytes());
+        }
+        for domain in &self.custom_domains {
+            put_field(&mut buf, TAG_CUSTOM_DOMAIN, domain.as_bytes());
+        }
+        if let Some(port) = self.tcp_port {
+            put_field(&mut buf, TAG_TCP_PORT, &port.to_be_bytes());
+        }
+        if let Some(port) = self.udp_port {
+            put_field(&mut buf, TAG_UDP_PORT, &port.to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, CodecError> {
+        let mut handshake = Handshake::default();
+        for (tag, value) in fields(payload)? {
+            match tag {
+                TAG_CLIENT_VERSION => handshake.client_version = field_str(value)?,
+                TAG_SUBDOMAIN => handshake.subdomain = Some(field_str(value)?),
+                TAG_TOKEN => handshake.token = Some(field_str(value)?),
+                TAG_RESUME_TOKEN => handshake.resume_token = Some(field_str(value)?),
+                TAG_CUSTOM_DOMAIN => handshake.custom_domains.push(field_str(value)?),
+                TAG_TCP_PORT => handshake.tcp_port = Some(field_u16(value)?),
+                TAG_UDP_PORT => handshake.udp_port = Some(field_u16(value)?),
+                _ => {} // unknown fields are skipped so newer clients still connect
+            }
+        }
+        Ok(handshake)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandshakeAck {
+    pub client_id: String,
+    pub resume_token: Option<String>,
+    // (domain, token) for every custom domain waiting on verification
+    pub domain_challenges: Vec<(String, String)>,
+    // the public ports of raw TCP and UDP tunnels
+    pub tcp_port: Option<u16>,
+    pub udp_port: Option<u16>,
+}
+
+impl HandshakeAck {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_field(&mut buf, TAG_CLIENT_ID, self.client_id.as_bytes());
+        if let Some(resume_token) = &self.resume_token {
+            put_field(&mut buf, TAG_RESUME_TOKEN, resume_token.as_bytes());
+        }
+        for (domain, token) in &self.domain_challenges {
+            put_field(
+                &mut buf,
+                TAG_DOMAIN_CHALLENGE,
+                format!("{domain} {token}").as_bytes(),
+            );
+        }
+        if let Some(port) = self.tcp_port {
+            put_field(&mut buf, TAG_TCP_PORT, &port.to_be_bytes());
+        }
+        if let Some(port) = self.udp_port {
+            put_field(&mut buf, TAG_UDP_PORT, &port.to_be_bytes());
+        }
+        buf
+    }
+
+    #[cfg(test)]
+    pub fn decode(payload: &[u8]) -> Result<Self, CodecError> {
+        let mut ack = HandshakeAck::default();
+        for (tag, value) in fields(payload)? {
+            match tag {
+                TAG_CLIENT_ID => ack.client_id = field_str(value)?,
+                TAG_RESUME_TOKEN => ack.resume_token = Some(field_str(value)?),
+                TAG_DOMAIN_CHALLENGE => {
+                    let value = field_str(value)?;
+                    if let Some((domain, token)) = value.split_once(' ') {
+                        ack.domain_challenges
+                            .push((domain.to_string(), token.to_string()));
+                    }
+                }
+                TAG_TCP_PORT => ack.tcp_port = Some(field_u16(value)?),
+                TAG_UDP_PORT => ack.udp_port = Some(field_u16(value)?),
+                _ => {}
+            }
+        }
+        Ok(ack)
+    }
+}
+
+// error payload: [code: u16 BE][message]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(2 + self.message.len());
+        buf.extend_from_slice(&(self.code as u16).to_be_bytes());
+        buf.extend_from_slice(self.message.as_bytes());
+        buf
+    }
+}
+
+fn put_field(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    let len = value.len().min(u16::MAX as usize);
+    buf.push(tag);
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    buf.extend_from_slice(&value[..len]);
+}
+
+fn fields(mut payload: &[u8]) -> Result<Vec<(u8, &[u8])>, CodecError> {
+    let mut result = Vec::new();
+    while !payload.is_empty() {
+        let Some((head, rest)) = payload.split_first_chunk::<3>() else {
+            return Err(CodecError::Truncated);
+        };
+        let len = u16::from_be_bytes([head[1], head[2]]) as usize;
+        if rest.len() < len {
+            return Err(CodecError::Truncated);
+        }
+        result.push((head[0], &rest[..len]));
+        payload = &rest[len..];
+    }
+    Ok(result)
+}
+
+fn field_str(value: &[u8]) -> Result<String, CodecError> {
+    String::from_utf8(value.to_vec()).map_err(|_| CodecError::InvalidUtf8)
+}
+
+fn field_u16(value: &[u8]) -> Result<u16, CodecError> {
+    let bytes: [u8; 2] = value.try_into().map_err(|_| CodecError::Truncated)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = Frame::open(7, b"GET / HTTP/1.1\r\n\r\n".to_vec());
+        let encoded = encode_frame(&frame);
+        assert_eq!(encoded.len(), FRAME_HEADER_LEN + frame.payload.len());
+
+        let (decoded, used) = decode_frame(&encoded).unwrap().unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!(used, encoded.len());
+    }
+
+    #[test]
+    fn test_decode_partial_frame() {
+        let encoded = encode_frame(&Frame::open(1, b"hello".to_vec()));
+        assert!(decode_frame(&encoded[..5]).unwrap().is_none());
+        assert!(
+            decode_frame(&encoded[..encoded.len() - 1])
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_decode_frame_followed_by_data() {
+        let mut encoded = encode_frame(&Frame::close(3));
+        let first_len = encoded.len();
+        encoded.extend(encode_frame(&Frame::open(4, b"x".to_vec())));
+
+        let (first, used) = decode_frame(&encoded).unwrap().unwrap();
+        assert_eq!(first.stream_id, 3);
+        assert_eq!(used, first_len);
+        let (second, _) = decode_frame(&encoded[used..]).unwrap().unwrap();
+        assert_eq!(second.payload, b"x");
+    }
+
+    #[test]
+    fn test_decode_wrong_version() {
+        let mut encoded = encode_frame(&Frame::close(1));
+        encoded[0] = 99;
+        assert!(matches!(
+            decode_frame(&encoded),
+            Err(CodecError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn test_decode_unknown_type() {
+        let mut encoded = encode_frame(&Frame::close(1));
+        encoded[1] = 200;
+        assert!(matches!(
+            decode_frame(&encoded),
+            Err(CodecError::UnknownFrameType(200))
+        ));
+    }
+
+    #[test]
+    fn test_decode_payload_too_large() {
+        let mut encoded = encode_frame(&Frame::close(1));
+        encoded[7..11].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            decode_frame(&encoded),
+            Err(CodecError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_handshake_roundtrip() {
+        let handshake = Handshake {
+            client_version: "0.1.0".to_string(),
+            subdomain: Some("myapp".to_string()),
+            token: Some("abc123".to_string()),
+            resume_token: Some("0123abcd".to_string()),
+            custom_domains: vec!["dev.customer.com".to_string(), "customer.org".to_string()],
+            tcp_port: Some(0),
+            udp_port: Some(5353),
+        };
+        assert_eq!(Handshake::decode(&handshake.encode()).unwrap(), handshake);
+
+        let anonymous = Handshake {
+            client_version: "0.1.0".to_string(),
+            subdomain: None,
+            token: None,
+            resume_token: None,
+            custom_domains: Vec::new(),
+            tcp_port: None,
+            udp_port: None,
+        };
+        assert_eq!(Handshake::decode(&anonymous.encode()).unwrap(), anonymous);
+    }
+
+    #[test]
+    fn test_handshake_skips_unknown_fields() {
+        let mut payload = Vec::new();
+        put_field(&mut payload, 250, b"future");
+        put_field(&mut payload, TAG_CLIENT_VERSION, b"0.1.0");
+        let handshake = Handshake::decode(&payload).unwrap();
+        assert_eq!(handshake.client_version, "0.1.0");
+    }
+
+    #[test]
+    fn test_handshake_truncated() {
+        let payload = Handshake {
+            client_version: "0.1.0".to_string(),
+            subdomain: None,
+            token: None,
+            resume_token: None,
+            custom_domains: Vec::new(),
+            tcp_port: None,
+            udp_port: None,
+        }
+        .encode();
+        assert!(matches!(
+            Handshake::decode(&payload[..payload.len() - 1]),
+            Err(CodecError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_ack_roundtrip() {
+        let ack = HandshakeAck {
+            client_id: "app-0001".to_string(),
+            resume_token: Some("0123abcd".to_string()),
+            domain_challenges: vec![("dev.customer.com".to_string(), "f00d".to_string())],
+            tcp_port: Some(20001),
+            udp_port: Some(20002),
+        };
+        assert_eq!(HandshakeAck::decode(&ack.encode()).unwrap(), ack);
+    }
+
+    #[test]
+    fn test_error_encode() {
+        let error = ErrorMessage::new(ErrorCode::ClientVersionTooOld, "need 0.0.2");
+        let encoded = error.encode();
+        assert_eq!(&encoded[..2], &[0, 1]);
+        assert_eq!(&encoded[2..], b"need 0.0.2");
+    }
+
+    #[test]
+    fn test_error_code_display() {
+        assert_eq!(
+            ErrorCode::ClientVersionTooOld.to_string(),
+            "ERR001:request_higher_version"
+        );
+    }
+}
@@ -0,0 +1,976 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::access_log::AccessLogFormat;
+use crate::codec::MAX_FRAME_PAYLOAD;
+use crate::http::DEFAULT_MAX_HEAD_SIZE;
+use crate::tcp_server::parse_version;
+use crate::tls::CertPaths;
+
+const ENV_PREFIX: &str = "BINDLOCAL_";
+
+/// Command line, every flag wins over the config file and the environment.
+#[derive(Parser, Debug, Default)]
+#[command(
+    name = "connl-server",
+    version,
+    about = "Exposes local servers on public subdomains"
+)]
+pub struct Cli {
+    /// Port for browsers, same as `--http-addr 0.0.0.0:<port>`
+    #[arg(conflicts_with = "http_addr")]
+    pub http_port: Option<u16>,
+
+    /// Port for tunnel clients, same as `--tcp-addr 0.0.0.0:<port>`
+    #[arg(conflicts_with = "tcp_addr")]
+    pub tcp_port: Option<u16>,
+
+    /// TOML config file
+    #[arg(short, long, env = "BINDLOCAL_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long)]
+    pub http_addr: Option<SocketAddr>,
+
+    #[arg(long)]
+    pub tcp_addr: Option<SocketAddr>,
+
+    /// Domain the tunnels live under, `<name>.<base domain>`, can be given more than once
+    #[arg(long = "base-domain")]
+    pub base_domains: Vec<String>,
+
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Lowest level written to the server log, such as `debug` or `warn`
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub http_addr: SocketAddr,
+    pub tcp_addr: SocketAddr,
+    // TLS passthrough, connections are routed by SNI and never decrypted
+    pub sni_addr: Option<SocketAddr>,
+    pub base_domains: Vec<String>,
+    // custom domains only go live once their DNS carries the owner's challenge token
+    pub verify_custom_domains: bool,
+    // the DNS server asked for challenge records
+    pub custom_domain_resolver: SocketAddr,
+    // serve tunnels under `/t/<name>/` when there is no wildcard DNS
+    pub path_routing: bool,
+    // public ports for raw TCP tunnels, bound on the HTTP listener's address
+    pub tcp_tunnel_ports: Option<PortRange>,
+    pub udp_tunnel_ports: Option<PortRange>,
+    // a UDP peer without traffic for this long loses its session
+    pub udp_session_idle_secs: u64,
+    // peers one UDP tunnel serves at once, datagrams from new ones are dropped beyond it
+    pub udp_max_sessions: usize,
+    pub log_dir: PathBuf,
+    // when the log files start over, and how many old ones are kept (`None` keeps all)
+    pub log_rotation: LogRotation,
+    pub log_max_files: Option<usize>,
+    pub log_level: String,
+    // one line per request in `access.log`, next to the server log
+    pub access_log_format: AccessLogFormat,
+    pub reservations_file: Option<String>,
+    pub reconnect_grace_secs: u64,
+    pub minimum_client_version: String,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub tunnel_tls: TunnelTlsConfig,
+    pub admin: AdminConfig,
+    pub capture: CaptureConfig,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub tokens_file: Option<String>,
+    pub hmac_secret: Option<String>,
+}
+
+/// Certificates for the HTTP listener, `cert` and `key` are paired by position.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Vec<String>,
+    pub key: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelTlsConfig {
+    pub cert: Vec<String>,
+    pub key: Vec<String>,
+    pub client_ca: Option<String>,
+}
+
+/// The admin API listener, off unless `addr` is set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub addr: Option<SocketAddr>,
+    // sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+}
+
+/// Recent requests of each tunnel kept for the admin API, off while `requests` is 0.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    // per tunnel, the oldest is dropped for a new one
+    pub requests: usize,
+    // request and response bodies are cut off after this many bytes
+    pub max_body_size: usize,
+}
+
+/// Sizes of the buffers and queues between browsers and tunnels.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_head_size: usize,
+    pub read_chunk_size: usize,
+    // chunks buffered between the browser and the tunnel in each direction
+    pub body_channel_capacity: usize,
+    pub response_channel_capacity: usize,
+    // response bytes queued per stream for a browser that reads slower than its tunnel
+    // sends, the whole tunnel waits for it beyond that
+    pub response_buffer_size: usize,
+    // frames waiting for the client socket, a full queue slows the senders down
+    pub frame_channel_capacity: usize,
+}
+
+/// How long browsers and tunnels get, running out is answered with 504 Gateway Timeout.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    // to send a request head, an idle keep-alive connection is closed after it
+    pub header_read_secs: u64,
+    // between two reads of a request body
+    pub body_read_secs: u64,
+    // from the end of the request body until the tunnel's first response bytes
+    pub first_byte_secs: u64,
+    // until the whole response is in, unset by default so event streams are not cut off
+    pub response_secs: Option<u64>,
+    // for a tunnel client to send its handshake after connecting
+    pub handshake_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8080),
+            tcp_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 9090),
+            sni_addr: None,
+            base_domains: Vec::new(),
+            verify_custom_domains: false,
+            custom_domain_resolver: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 53),
+            path_routing: false,
+            tcp_tunnel_ports: None,
+            udp_tunnel_ports: None,
+            udp_session_idle_secs: 60,
+            udp_max_sessions: 256,
+            log_dir: PathBuf::from("logs"),
+            log_rotation: LogRotation::Daily,
+            log_max_files: None,
+            log_level: "info".to_string(),
+            access_log_format: AccessLogFormat::Combined,
+            reservations_file: None,
+            reconnect_grace_secs: 30,
+            minimum_client_version: "0.0.2".to_string(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+            tunnel_tls: TunnelTlsConfig::default(),
+            admin: AdminConfig::default(),
+            capture: CaptureConfig::default(),
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            requests: 0,
+            max_body_size: 16 * 1024,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header_read_secs: 30,
+            body_read_secs: 60,
+            first_byte_secs: 60,
+            response_secs: None,
+            handshake_secs: 10,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+            read_chunk_size: 4096,
+            body_channel_capacity: 16,
+            response_channel_capacity: 16,
+            response_buffer_size: 1024 * 1024,
+            frame_channel_capacity: 64,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn cert_paths(&self) -> Vec<CertPaths> {
+        pair_certs(&self.cert, &self.key)
+    }
+}
+
+impl TunnelTlsConfig {
+    pub fn cert_paths(&self) -> Vec<CertPaths> {
+        pair_certs(&self.cert, &self.key)
+    }
+}
+
+impl ServerConfig {
+    /// Defaults, then the config file, then `BINDLOCAL_*` variables, then the command line.
+    pub fn load<E>(cli: &Cli, env: E) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env(env)?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read config file {}: {e}", path.display()))?;
+        Self::parse(&content).map_err(|e| format!("{}: {e}", path.display()).into())
+    }
+
+    pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    /// Every key has a variable named after its path, `[auth] tokens_file` is `BINDLOCAL_AUTH_TOKENS_FILE`.
+    fn apply_env<E>(&mut self, env: E) -> Result<(), String>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let var = |name: &str| env(&format!("{ENV_PREFIX}{name}")).filter(|v| !v.is_empty());
+
+        override_parsed(&var, "HTTP_ADDR", &mut self.http_addr)?;
+        override_parsed(&var, "TCP_ADDR", &mut self.tcp_addr)?;
+        override_parsed_optional(&var, "SNI_ADDR", &mut self.sni_addr)?;
+        override_list(&var, "BASE_DOMAINS", &mut self.base_domains);
+        override_parsed(
+            &var,
+            "VERIFY_CUSTOM_DOMAINS",
+            &mut self.verify_custom_domains,
+        )?;
+        override_parsed(
+            &var,
+            "CUSTOM_DOMAIN_RESOLVER",
+            &mut self.custom_domain_resolver,
+        )?;
+        override_parsed(&var, "PATH_ROUTING", &mut self.path_routing)?;
+        override_parsed_optional(&var, "TCP_TUNNEL_PORTS", &mut self.tcp_tunnel_ports)?;
+        override_parsed_optional(&var, "UDP_TUNNEL_PORTS", &mut self.udp_tunnel_ports)?;
+        override_parsed(
+            &var,
+            "UDP_SESSION_IDLE_SECS",
+            &mut self.udp_session_idle_secs,
+        )?;
+        override_parsed(&var, "UDP_MAX_SESSIONS", &mut self.udp_max_sessions)?;
+        override_parsed(&var, "LOG_DIR", &mut self.log_dir)?;
+        override_parsed(&var, "LOG_ROTATION", &mut self.log_rotation)?;
+        override_parsed_optional(&var, "LOG_MAX_FILES", &mut self.log_max_files)?;
+        override_parsed(&var, "LOG_LEVEL", &mut self.log_level)?;
+        override_parsed(&var, "ACCESS_LOG_FORMAT", &mut self.access_log_format)?;
+        override_optional(&var, "RESERVATIONS_FILE", &mut self.reservations_file);
+        override_parsed(&var, "RECONNECT_GRACE_SECS", &mut self.reconnect_grace_secs)?;
+        override_parsed(
+            &var,
+            "MINIMUM_CLIENT_VERSION",
+            &mut self.minimum_client_version,
+        )?;
+
+        override_optional(&var, "AUTH_TOKENS_FILE", &mut self.auth.tokens_file);
+        override_optional(&var, "AUTH_HMAC_SECRET", &mut self.auth.hmac_secret);
+
+        override_list(&var, "TLS_CERT", &mut self.tls.cert);
+        override_list(&var, "TLS_KEY", &mut self.tls.key);
+        override_list(&var, "TUNNEL_TLS_CERT", &mut self.tunnel_tls.cert);
+        override_list(&var, "TUNNEL_TLS_KEY", &mut self.tunnel_tls.key);
+        override_optional(&var, "TUNNEL_TLS_CLIENT_CA", &mut self.tunnel_tls.client_ca);
+
+        override_parsed_optional(&var, "ADMIN_ADDR", &mut self.admin.addr)?;
+        override_optional(&var, "ADMIN_TOKEN", &mut self.admin.token);
+
+        override_parsed(&var, "CAPTURE_REQUESTS", &mut self.capture.requests)?;
+        override_parsed(
+            &var,
+            "CAPTURE_MAX_BODY_SIZE",
+            &mut self.capture.max_body_size,
+        )?;
+
+        let limits = &mut self.limits;
+        override_parsed(&var, "LIMITS_MAX_HEAD_SIZE", &mut limits.max_head_size)?;
+        override_parsed(&var, "LIMITS_READ_CHUNK_SIZE", &mut limits.read_chunk_size)?;
+        override_parsed(
+            &var,
+            "LIMITS_BODY_CHANNEL_CAPACITY",
+            &mut limits.body_channel_capacity,
+        )?;
+        override_parsed(
+            &var,
+            "LIMITS_RESPONSE_CHANNEL_CAPACITY",
+            &mut limits.response_channel_capacity,
+        )?;
+        override_parsed(
+            &var,
+            "LIMITS_RESPONSE_BUFFER_SIZE",
+            &mut limits.response_buffer_size,
+        )?;
+        override_parsed(
+            &var,
+            "LIMITS_FRAME_CHANNEL_CAPACITY",
+            &mut limits.frame_channel_capacity,
+        )?;
+
+        let timeouts = &mut self.timeouts;
+        override_parsed(
+            &var,
+            "TIMEOUTS_HEADER_READ_SECS",
+            &mut timeouts.header_read_secs,
+        )?;
+        override_parsed(
+            &var,
+            "TIMEOUTS_BODY_READ_SECS",
+            &mut timeouts.body_read_secs,
+        )?;
+        override_parsed(
+            &var,
+            "TIMEOUTS_FIRST_BYTE_SECS",
+            &mut timeouts.first_byte_secs,
+        )?;
+        override_parsed_optional(&var, "TIMEOUTS_RESPONSE_SECS", &mut timeouts.response_secs)?;
+        override_parsed(
+            &var,
+            "TIMEOUTS_HANDSHAKE_SECS",
+            &mut timeouts.handshake_secs,
+        )?;
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        let any_address = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        if let Some(port) = cli.http_port {
+            self.http_addr = SocketAddr::new(any_address, port);
+        }
+        if let Some(port) = cli.tcp_port {
+            self.tcp_addr = SocketAddr::new(any_address, port);
+        }
+        if let Some(addr) = cli.http_addr {
+            self.http_addr = addr;
+        }
+        if let Some(addr) = cli.tcp_addr {
+            self.tcp_addr = addr;
+        }
+        if !cli.base_domains.is_empty() {
+            self.base_domains = cli.base_domains.clone();
+        }
+        if let Some(log_dir) = &cli.log_dir {
+            self.log_dir = log_dir.clone();
+        }
+        if let Some(log_level) = &cli.log_level {
+            self.log_level = log_level.clone();
+        }
+    }
+
+    fn validate(&mut self) -> Result<(), String> {
+        if self.http_addr == self.tcp_addr {
+            return Err(format!(
+                "http_addr and tcp_addr are both {}",
+                self.http_addr
+            ));
+        }
+
+        if let Some(sni_addr) = self.sni_addr
+            && (sni_addr == self.http_addr || sni_addr == self.tcp_addr)
+        {
+            return Err(format!(
+                "sni_addr {sni_addr} is already used by another listener"
+            ));
+        }
+
+        if let Some(admin_addr) = self.admin.addr {
+            if [Some(self.http_addr), Some(self.tcp_addr), self.sni_addr]
+                .contains(&Some(admin_addr))
+            {
+                return Err(format!(
+                    "admin.addr {admin_addr} is already used by another listener"
+                ));
+            }
+            if self.admin.token.as_deref().is_none_or(str::is_empty) {
+                return Err("admin.addr needs an admin.token".to_string());
+            }
+        }
+        // captures are only ever read through the admin API
+        if self.capture.requests > 0 && self.admin.addr.is_none() {
+            return Err("capture.requests needs an admin.addr".to_string());
+        }
+
+        for base_domain in &mut self.base_domains {
+            let normalized = base_domain.trim_matches('.').to_ascii_lowercase();
+            if !is_valid_domain(&normalized) {
+                return Err(format!(
+                    "base_domains: {base_domain:?} is not a domain name"
+                ));
+            }
+            *base_domain = normalized;
+        }
+
+        // checked the way client versions are, anything else would turn every client away
+        if parse_version(&self.minimum_client_version).is_none() {
+            return Err(format!(
+                "minimum_client_version: {:?} is not a MAJOR.MINOR.PATCH version such as 0.0.2",
+                self.minimum_client_version
+            ));
+        }
+
+        if let Some(ports) = &self.tcp_tunnel_ports {
+            for addr in [Some(self.http_addr), Some(self.tcp_addr), self.sni_addr]
+                .into_iter()
+                .flatten()
+            {
+                if ports.contains(addr.port()) {
+                    return Err(format!(
+                        "tcp_tunnel_ports: {ports} overlaps the listener on {addr}"
+                    ));
+                }
+            }
+        }
+
+        if self
+            .log_level
+            .parse::<tracing_subscriber::filter::LevelFilter>()
+            .is_err()
+        {
+            return Err(format!(
+                "log_level: {:?} is not one of trace, debug, info, warn, error or off",
+                self.log_level
+            ));
+        }
+        if self.log_max_files == Some(0) {
+            return Err("log_max_files must be at least 1".to_string());
+        }
+
+        if self.udp_session_idle_secs == 0 {
+            return Err("udp_session_idle_secs must be at least 1".to_string());
+        }
+        if self.udp_max_sessions == 0 {
+            return Err("udp_max_sessions must be at least 1".to_string());
+        }
+
+        let limits = &self.limits;
+        for (name, value, minimum) in [
+            ("limits.max_head_size", limits.max_head_size, 1024),
+            ("limits.read_chunk_size", limits.read_chunk_size, 512),
+            (
+                "limits.body_channel_capacity",
+                limits.body_channel_capacity,
+                1,
+            ),
+            (
+                "limits.response_channel_capacity",
+                limits.response_channel_capacity,
+                1,
+            ),
+            (
+                "limits.response_buffer_size",
+                limits.response_buffer_size,
+                4096,
+            ),
+            (
+                "limits.frame_channel_capacity",
+                limits.frame_channel_capacity,
+                1,
+            ),
+        ] {
+            if value < minimum {
+                return Err(format!("{name} is {value}, it must be at least {minimum}"));
+            }
+        }
+        // a request head and the first body bytes read with it go out as one frame, which the
+        // client refuses past `MAX_FRAME_PAYLOAD`
+        let maximum = MAX_FRAME_PAYLOAD / 2;
+        for (name, value) in [
+            ("limits.max_head_size", limits.max_head_size),
+            ("limits.read_chunk_size", limits.read_chunk_size),
+        ] {
+            if value > maximum {
+                return Err(format!("{name} is {value}, it must be at most {maximum}"));
+            }
+        }
+
+        let timeouts = &self.timeouts;
+        for (name, value) in [
+            ("timeouts.header_read_secs", Some(timeouts.header_read_secs)),
+            ("timeouts.body_read_secs", Some(timeouts.body_read_secs)),
+            ("timeouts.first_byte_secs", Some(timeouts.first_byte_secs)),
+            ("timeouts.response_secs", timeouts.response_secs),
+            ("timeouts.handshake_secs", Some(timeouts.handshake_secs)),
+        ] {
+            if value == Some(0) {
+                return Err(format!("{name} must be at least 1"));
+            }
+        }
+
+        check_pairs("tls", &self.tls.cert, &self.tls.key)?;
+        check_pairs("tunnel_tls", &self.tunnel_tls.cert, &self.tunnel_tls.key)?;
+        if self.tunnel_tls.client_ca.is_some() && self.tunnel_tls.cert.is_empty() {
+            return Err("tunnel_tls.client_ca needs a tunnel_tls.cert to serve".to_string());
+        }
+
+        let files = [
+            (
+                "auth.tokens_file",
+                self.auth.tokens_file.iter().collect::<Vec<_>>(),
+            ),
+            ("reservations_file", self.reservations_file.iter().collect()),
+            ("tls.cert", self.tls.cert.iter().collect()),
+            ("tls.key", self.tls.key.iter().collect()),
+            ("tunnel_tls.cert", self.tunnel_tls.cert.iter().collect()),
+            ("tunnel_tls.key", self.tunnel_tls.key.iter().collect()),
+            (
+                "tunnel_tls.client_ca",
+                self.tunnel_tls.client_ca.iter().collect(),
+            ),
+        ];
+        for (name, paths) in files {
+            for path in paths {
+                if !Path::new(path).is_file() {
+                    return Err(format!("{name}: {path} does not exist or is not a file"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "minutely" => Ok(LogRotation::Minutely),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            "weekly" => Ok(LogRotation::Weekly),
+            "never" => Ok(LogRotation::Never),
+            _ => Err("expected minutely, hourly, daily, weekly or never".to_string()),
+        }
+    }
+}
+
+/// An inclusive port range written as `20000-20099`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (start, end) = value.split_once('-').unwrap_or((value, value));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("{port:?} is not a port: {e}"))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == 0 || start > end {
+            return Err(format!("{value:?} is not a range such as 20000-20099"));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+fn override_parsed<V, T>(var: &V, name: &str, target: &mut T) -> Result<(), String>
+where
+    V: Fn(&str) -> Option<String>,
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(name) {
+        *target = value
+            .parse()
+            .map_err(|e| format!("{ENV_PREFIX}{name}: invalid value {value:?}: {e}"))?;
+    }
+    Ok(())
+}
+
+fn override_parsed_optional<V, T>(var: &V, name: &str, target: &mut Option<T>) -> Result<(), String>
+where
+    V: Fn(&str) -> Option<String>,
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(name) {
+        let parsed = value
+            .parse()
+            .map_err(|e| format!("{ENV_PREFIX}{name}: invalid value {value:?}: {e}"))?;
+        *target = Some(parsed);
+    }
+    Ok(())
+}
+
+fn override_optional<V>(var: &V, name: &str, target: &mut Option<String>)
+where
+    V: Fn(&str) -> Option<String>,
+{
+    if let Some(value) = var(name) {
+        *target = Some(value);
+    }
+}
+
+// lists are comma separated in the environment
+fn override_list<V>(var: &V, name: &str, target: &mut Vec<String>)
+where
+    V: Fn(&str) -> Option<String>,
+{
+    if let Some(value) = var(name) {
+        *target = value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+    }
+}
+
+fn check_pairs(section: &str, certs: &[String], keys: &[String]) -> Result<(), String> {
+    if certs.len() != keys.len() {
+        return Err(format!(
+            "{section}: {} certificate(s) but {} key(s), they are paired by position",
+            certs.len(),
+            keys.len()
+        ));
+    }
+    Ok(())
+}
+
+fn pair_certs(certs: &[String], keys: &[String]) -> Vec<CertPaths> {
+    certs
+        .iter()
+        .zip(keys)
+        .map(|(cert, key)| CertPaths {
+            cert: cert.into(),
+            key: key.into(),
+        })
+        .collect()
+}
+
+pub fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<ServerConfig, String> {
+        let cli = Cli::try_parse_from(std::iter::once("connl-server").chain(args.iter().copied()))
+            .map_err(|e| e.to_string())?;
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        ServerConfig::load(&cli, |name| env.get(name).cloned()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_server_config_default_ports() {
+        let config = load(&[], &[]).unwrap();
+        assert_eq!(config.http_addr.port(), 8080);
+        assert_eq!(config.tcp_addr.port(), 9090);
+        assert_eq!(config.http_addr.to_string(), "0.0.0.0:8080");
+        assert_eq!(config.tcp_addr.to_string(), "0.0.0.0:9090");
+        assert_eq!(config, ServerConfig::default());
+    }
+
+    #[test]
+    fn test_server_config_custom_ports() {
+        let config = load(&["3000", "4000"], &[]).unwrap();
+        assert_eq!(config.http_addr.to_string(), "0.0.0.0:3000");
+        assert_eq!(config.tcp_addr.to_string(), "0.0.0.0:4000");
+        assert!(load(&["http"], &[]).is_err());
+        assert!(load(&["3000", "--http-addr", "127.0.0.1:80"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let config = ServerConfig::parse(
+            r#"
+            http_addr = "127.0.0.1:80"
+            base_domains = ["example.com", "example.org"]
+            reconnect_grace_secs = 5
+            tcp_tunnel_ports = "20000-20099"
+
+            [auth]
+            hmac_secret = "s3cret"
+
+            [limits]
+            max_head_size = 8192
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.http_addr.to_string(), "127.0.0.1:80");
+        assert_eq!(config.tcp_addr.to_string(), "0.0.0.0:9090");
+        assert_eq!(config.base_domains, ["example.com", "example.org"]);
+        assert_eq!(config.reconnect_grace_secs, 5);
+        assert_eq!(
+            config.tcp_tunnel_ports,
+            Some(PortRange {
+                start: 20000,
+                end: 20099
+            })
+        );
+        assert_eq!(config.auth.hmac_secret.as_deref(), Some("s3cret"));
+        assert_eq!(config.limits.max_head_size, 8192);
+        assert_eq!(config.limits.read_chunk_size, 4096);
+    }
+
+    #[test]
+    fn test_parse_file_errors_name_the_key() {
+        let error = ServerConfig::parse("http_port = 80")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("http_port"), "{error}");
+        let error = ServerConfig::parse("[limits]\nmax_head_size = \"big\"")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("max_head_size"), "{error}");
+        let error = ServerConfig::parse("tcp_addr = \"nowhere\"")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("tcp_addr"), "{error}");
+    }
+
+    #[test]
+    fn test_layers() {
+        let dir = std::env::temp_dir().join(format!("connl-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("server.toml");
+        std::fs::write(
+            &file,
+            "http_addr = \"127.0.0.1:1000\"\ntcp_addr = \"127.0.0.1:2000\"\nreconnect_grace_secs = 1\n",
+        )
+        .unwrap();
+        let file = file.to_str().unwrap();
+
+        let config = load(&["--config", file], &[]).unwrap();
+        assert_eq!(config.http_addr.to_string(), "127.0.0.1:1000");
+
+        // the environment beats the file, the command line beats both
+        let env = [
+            ("BINDLOCAL_HTTP_ADDR", "127.0.0.1:1001"),
+            ("BINDLOCAL_RECONNECT_GRACE_SECS", "7"),
+        ];
+        let config = load(&["--config", file], &env).unwrap();
+        assert_eq!(config.http_addr.to_string(), "127.0.0.1:1001");
+        assert_eq!(config.reconnect_grace_secs, 7);
+        let config = load(&["--config", file, "--http-addr", "127.0.0.1:1002"], &env).unwrap();
+        assert_eq!(config.http_addr.to_string(), "127.0.0.1:1002");
+        assert_eq!(config.tcp_addr.to_string(), "127.0.0.1:2000");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let config = load(
+            &[],
+            &[
+                ("BINDLOCAL_AUTH_HMAC_SECRET", "secret"),
+                ("BINDLOCAL_LIMITS_FRAME_CHANNEL_CAPACITY", "128"),
+                ("BINDLOCAL_LOG_ROTATION", "Hourly"),
+                ("BINDLOCAL_LOG_MAX_FILES", "7"),
+                ("BINDLOCAL_ACCESS_LOG_FORMAT", "json"),
+                ("BINDLOCAL_TIMEOUTS_RESPONSE_SECS", "300"),
+                (
+                    "BINDLOCAL_BASE_DOMAINS",
+                    ".Example.COM., tunnel.example.org",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.auth.hmac_secret.as_deref(), Some("secret"));
+        assert_eq!(config.limits.frame_channel_capacity, 128);
+        assert_eq!(config.base_domains, ["example.com", "tunnel.example.org"]);
+        assert_eq!(config.log_rotation, LogRotation::Hourly);
+        assert_eq!(config.log_max_files, Some(7));
+        assert_eq!(config.access_log_format, AccessLogFormat::Json);
+        assert_eq!(config.timeouts.response_secs, Some(300));
+        assert_eq!(config.timeouts.first_byte_secs, 60);
+
+        let error = load(&[], &[("BINDLOCAL_RECONNECT_GRACE_SECS", "soon")]).unwrap_err();
+        assert!(
+            error.starts_with("BINDLOCAL_RECONNECT_GRACE_SECS: invalid value"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let error = load(&["8080", "8080"], &[]).unwrap_err();
+        assert_eq!(error, "http_addr and tcp_addr are both 0.0.0.0:8080");
+        let error = load(&["--log-level", "loud"], &[]).unwrap_err();
+        assert!(error.starts_with("log_level:"), "{error}");
+
+        let error = load(&[], &[("BINDLOCAL_ADMIN_ADDR", "127.0.0.1:9100")]).unwrap_err();
+        assert_eq!(error, "admin.addr needs an admin.token");
+        let error = load(&[], &[("BINDLOCAL_CAPTURE_REQUESTS", "20")]).unwrap_err();
+        assert_eq!(error, "capture.requests needs an admin.addr");
+        let error = load(&[], &[("BINDLOCAL_TIMEOUTS_FIRST_BYTE_SECS", "0")]).unwrap_err();
+        assert_eq!(error, "timeouts.first_byte_secs must be at least 1");
+
+        let error = load(&[], &[("BINDLOCAL_SNI_ADDR", "0.0.0.0:9090")]).unwrap_err();
+        assert_eq!(
+            error,
+            "sni_addr 0.0.0.0:9090 is already used by another listener"
+        );
+
+        let error = load(&[], &[("BINDLOCAL_TCP_TUNNEL_PORTS", "9000-9100")]).unwrap_err();
+        assert_eq!(
+            error,
+            "tcp_tunnel_ports: 9000-9100 overlaps the listener on 0.0.0.0:9090"
+        );
+        let error = load(&[], &[("BINDLOCAL_TCP_TUNNEL_PORTS", "200-100")]).unwrap_err();
+        assert!(
+            error.starts_with("BINDLOCAL_TCP_TUNNEL_PORTS: invalid value"),
+            "{error}"
+        );
+
+        let error = load(&[], &[("BINDLOCAL_UDP_MAX_SESSIONS", "0")]).unwrap_err();
+        assert_eq!(error, "udp_max_sessions must be at least 1");
+
+        let error = load(&["--base-domain", "exa mple.com"], &[]).unwrap_err();
+        assert!(error.starts_with("base_domains:"), "{error}");
+
+        let error = load(&[], &[("BINDLOCAL_LIMITS_MAX_HEAD_SIZE", "10")]).unwrap_err();
+        assert_eq!(
+            error,
+            "limits.max_head_size is 10, it must be at least 1024"
+        );
+        let error = load(&[], &[("BINDLOCAL_LIMITS_MAX_HEAD_SIZE", "2000000")]).unwrap_err();
+        assert_eq!(
+            error,
+            "limits.max_head_size is 2000000, it must be at most 524288"
+        );
+        let error = load(&[], &[("BINDLOCAL_LIMITS_READ_CHUNK_SIZE", "1048576")]).unwrap_err();
+        assert_eq!(
+            error,
+            "limits.read_chunk_size is 1048576, it must be at most 524288"
+        );
+        let config = load(&[], &[("BINDLOCAL_LIMITS_MAX_HEAD_SIZE", "524288")]).unwrap();
+        assert_eq!(config.limits.max_head_size, 524288);
+
+        for version in ["v1", "1", "1.0", "1.2.3.4", "1..3"] {
+            let error = load(&[], &[("BINDLOCAL_MINIMUM_CLIENT_VERSION", version)]).unwrap_err();
+            assert!(error.starts_with("minimum_client_version:"), "{error}");
+        }
+        let config = load(&[], &[("BINDLOCAL_MINIMUM_CLIENT_VERSION", "1.2.3")]).unwrap();
+        assert_eq!(config.minimum_client_version, "1.2.3");
+
+        let error = load(
+            &[],
+            &[
+                ("BINDLOCAL_TLS_CERT", "a.pem,b.pem"),
+                ("BINDLOCAL_TLS_KEY", "a.key"),
+            ],
+        )
+        .unwrap_err();
+        assert!(
+            error.starts_with("tls: 2 certificate(s) but 1 key(s)"),
+            "{error}"
+        );
+
+        let error = load(&[], &[("BINDLOCAL_TUNNEL_TLS_CLIENT_CA", "ca.pem")]).unwrap_err();
+        assert!(error.starts_with("tunnel_tls.client_ca"), "{error}");
+
+        let error = load(
+            &[],
+            &[("BINDLOCAL_AUTH_TOKENS_FILE", "/nonexistent/tokens")],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "auth.tokens_file: /nonexistent/tokens does not exist or is not a file"
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config::is_valid_domain;
+use crate::dns::lookup_txt;
+use crate::shared::SharedState;
+
+// the TXT record under a custom domain that carries its challenge token
+pub const CHALLENGE_LABEL: &str = "_bindlocal-challenge";
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Entry {
+    // who claimed the domain, with verification the one whose token is in its DNS
+    owner: String,
+    // the tunnel serving it, `None` while a verified owner is not connected
+    client_id: Option<String>,
+}
+
+/// Hostnames outside the base domains that point at a tunnel, usually through a CNAME.
+#[derive(Clone)]
+pub struct CustomDomains {
+    table: Arc<RwLock<HashMap<String, Entry>>>,
+    // names under these belong to the subdomain registry
+    base_domains: Arc<Vec<String>>,
+    verify: bool,
+    // asked for the challenge records
+    resolver: SocketAddr,
+}
+
+impl CustomDomains {
+    pub fn new(base_domains: &[String], verify: bool, resolver: SocketAddr) -> Self {
+        Self {
+            table: Arc::new(RwLock::new(HashMap::new())),
+            base_domains: Arc::new(base_domains.to_vec()),
+            verify,
+            resolver,
+        }
+    }
+
+    /// Points `domain` at `client_id`. With verification on, a domain `owner` has not
+    /// verified yet stays off and the token for its TXT record is returned.
+    pub fn claim(
+        &self,
+        domain: &str,
+        owner: &str,
+        client_id: &str,
+    ) -> Result<Option<String>, String> {
+        let domain = normalize(domain);
+        if !is_valid_domain(&domain) {
+            return Err(format!("{domain:?} is not a domain name"));
+        }
+        if self
+            .base_domains
+            .iter()
+            .any(|base| domain == *base || domain.ends_with(&format!(".{base}")))
+        {
+            return Err(format!("{domain} is under a base domain"));
+        }
+
+        let mut table = self.table.write().unwrap();
+        match table.get_mut(&domain) {
+            Some(entry) if entry.owner == owner => {
+                if entry
+                    .client_id
+                    .as_ref()
+                    .is_some_and(|current| current != client_id)
+                {
+                    return Err(format!("{domain} is served by another tunnel"));
+                }
+                entry.client_id = Some(client_id.to_string());
+                Ok(None)
+            }
+            // the DNS record decides, it may move the domain to a new owner
+            _ if self.verify => Ok(Some(challenge_token(owner, &domain))),
+            Some(_) => Err(format!("{domain} belongs to another tunnel")),
+            None => {
+                table.insert(
+                    domain,
+                    Entry {
+                        owner: owner.to_string(),
+                        client_id: Some(client_id.to_string()),
+                    },
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Puts a domain live for `owner` once its challenge record was found.
+    fn activate(&self, domain: &str, owner: &str, client_id: &str) {
+        let mut table = self.table.write().unwrap();
+        let entry = Entry {
+            owner: owner.to_string(),
+            client_id: Some(client_id.to_string()),
+        };
+        if let Some(previous) = table.insert(normalize(domain), entry)
+            && previous.owner != owner
+        {
+            tracing::warn!(
+                "custom domain {domain} moved from {} to {owner}",
+                previous.owner
+            );
+        }
+    }
+
+    /// The tunnel a live custom domain points at.
+    pub fn lookup(&self, host: &str) -> Option<String> {
+        let table = self.table.read().unwrap();
+        table
+            .get(&normalize(host))
+            .and_then(|entry| entry.client_id.clone())
+    }
+
+    /// Points every domain of a renamed tunnel at its new name.
+    pub fn reassign(&self, client_id: &str, new_client_id: &str) {
+        let mut table = self.table.write().unwrap();
+        for entry in table.values_mut() {
+            if entry.client_id.as_deref() == Some(client_id) {
+                entry.client_id = Some(new_client_id.to_string());
+            }
+        }
+    }
+
+    /// Takes the domains of a tunnel offline. Verified ones stay with their owner for
+    /// its next connection, the others are forgotten and claimed again.
+    pub fn release(&self, client_id: &str) {
+        let mut table = self.table.write().unwrap();
+        if self.verify {
+            for entry in table.values_mut() {
+                if entry.client_id.as_deref() == Some(client_id) {
+                    entry.client_id = None;
+                }
+            }
+        } else {
+            table.retain(|_, entry| entry.client_id.as_deref() != Some(client_id));
+        }
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_matches('.').to_ascii_lowercase()
+}
+
+/// What `owner` publishes in the TXT record of `domain`. Anyone can work it out, only whoever
+/// controls the DNS of the domain can publish it.
+pub fn challenge_token(owner: &str, domain: &str) -> String {
+    let digest = Sha256::digest(format!("{owner}\n{}", normalize(domain)));
+    hex::encode(&digest[..16])
+}
+
+/// Looks for the challenge token in the DNS of `domain` and puts the mapping live if it is there.
+pub async fn verify(
+    shared_state: SharedState,
+    client_id: String,
+    owner: String,
+    domain: String,
+    token: String,
+) {
+    let domains = &shared_state.custom_domains;
+    let name = format!("{CHALLENGE_LABEL}.{domain}");
+    let found = match lookup_txt(domains.resolver, &name, CHALLENGE_TIMEOUT).await {
+        Ok(records) => records.iter().any(|record| record.trim() == token),
+        Err(e) => {
+            tracing::warn!("cannot look up {name}: {e}");
+            false
+        }
+    };
+    if !found {
+        tracing::warn!(
+            "custom domain {domain} failed verification for [{client_id}], no TXT record {name} with its token"
+        );
+        return;
+    }
+
+    // the tunnel may have gone or been renamed meanwhile, its name could be someone else's now
+    let connections = shared_state.tcp_connections.lock().await;
+    if connections
+        .get(&client_id)
+        .is_some_and(|tunnel| tunnel.info.owner == owner)
+    {
+        domains.activate(&domain, &owner, &client_id);
+        tracing::info!("custom domain {domain} verified for [{client_id}]");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains(base_domains: &[&str], verify: bool) -> CustomDomains {
+        let base_domains: Vec<String> = base_domains.iter().map(|d| d.to_string()).collect();
+        CustomDomains::new(&base_domains, verify, ([127, 0, 0, 1], 53).into())
+    }
+
+    #[test]
+    fn test_claim_without_verification() {
+        let domains = domains(&["example.com"], false);
+        assert_eq!(
+            domains.claim("Dev.Customer.com.", "alice", "app-1"),
+            Ok(None)
+        );
+        assert_eq!(
+            domains.lookup("dev.customer.com"),
+            Some("app-1".to_string())
+        );
+        assert_eq!(domains.lookup("other.customer.com"), None);
+
+        // the owner can claim again, nobody else can
+        assert_eq!(
+            domains.claim("dev.customer.com", "alice", "app-1"),
+            Ok(None)
+        );
+        assert!(domains.claim("dev.customer.com", "bob", "app-2").is_err());
+        assert!(domains.claim("dev.customer.com", "alice", "app-2").is_err());
+
+        domains.reassign("app-1", "app-3");
+        assert_eq!(
+            domains.lookup("dev.customer.com"),
+            Some("app-3".to_string())
+        );
+
+        domains.release("app-3");
+        assert_eq!(domains.lookup("dev.customer.com"), None);
+        assert_eq!(domains.claim("dev.customer.com", "bob", "app-2"), Ok(None));
+    }
+
+    #[test]
+    fn test_claim_rejects_base_domains_and_bad_names() {
+        let domains = domains(&["example.com"], false);
+        assert!(domains.claim("example.com", "alice", "app-1").is_err());
+        assert!(
+            domains
+                .claim("other.example.com", "alice", "app-1")
+                .is_err()
+        );
+        assert!(domains.claim("not a domain", "alice", "app-1").is_err());
+        assert!(domains.claim("notexample.com", "alice", "app-1").is_ok());
+    }
+
+    #[test]
+    fn test_verified_domain_stays_with_its_owner() {
+        let domains = domains(&[], true);
+        let token = domains.claim("dev.customer.com", "alice", "app-1").unwrap();
+        assert_eq!(token, Some(challenge_token("alice", "dev.customer.com")));
+        assert_eq!(domains.lookup("dev.customer.com"), None);
+
+        domains.activate("dev.customer.com", "alice", "app-1");
+        assert_eq!(
+            domains.lookup("dev.customer.com"),
+            Some("app-1".to_string())
+        );
+
+        // offline, but nobody else gets it without the DNS record
+        domains.release("app-1");
+        assert_eq!(domains.lookup("dev.customer.com"), None);
+        let token = domains
+            .claim("dev.customer.com", "mallory", "app-2")
+            .unwrap();
+        assert_eq!(token, Some(challenge_token("mallory", "dev.customer.com")));
+        assert_eq!(domains.lookup("dev.customer.com"), None);
+
+        // the owner comes back under another name and is live right away
+        assert_eq!(
+            domains.claim("dev.customer.com", "alice", "app-7"),
+            Ok(None)
+        );
+        assert_eq!(
+            domains.lookup("dev.customer.com"),
+            Some("app-7".to_string())
+        );
+    }
+
+    #[test]
+    fn test_challenge_token() {
+        let token = challenge_token("alice", "dev.customer.com");
+        assert_eq!(token.len(), 32);
+        assert_eq!(token, challenge_token("alice", "Dev.Customer.com."));
+        assert_ne!(token, challenge_token("bob", "dev.customer.com"));
+        assert_ne!(token, challenge_token("alice", "api.customer.com"));
+    }
+}
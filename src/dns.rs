@@ -0,0 +1,263 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const HEADER_LEN: usize = 12;
+// recursion desired
+const FLAGS_QUERY: u16 = 0x0100;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u16 = 3;
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+// the largest answer a resolver sends over UDP without EDNS
+const MAX_UDP_ANSWER: usize = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    InvalidName,
+    Timeout,
+    // the answer did not fit into a UDP datagram
+    Truncated,
+    Malformed,
+    // any response code other than no error and no such name
+    Failed(u16),
+    Io(String),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::InvalidName => write!(f, "not a DNS name"),
+            DnsError::Timeout => write!(f, "no answer from the resolver in time"),
+            DnsError::Truncated => write!(f, "answer too large for UDP"),
+            DnsError::Malformed => write!(f, "malformed DNS answer"),
+            DnsError::Failed(rcode) => write!(f, "resolver answered with rcode {rcode}"),
+            DnsError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+/// The TXT records of `name`, each with its strings joined. A name that does not exist has none.
+pub async fn lookup_txt(
+    resolver: SocketAddr,
+    name: &str,
+    timeout: Duration,
+) -> Result<Vec<String>, DnsError> {
+    let id = rand::random::<u16>();
+    let query = encode_query(id, name)?;
+    let io = |e: std::io::Error| DnsError::Io(e.to_string());
+    let local: SocketAddr = if resolver.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await.map_err(io)?;
+    socket.connect(resolver).await.map_err(io)?;
+    socket.send(&query).await.map_err(io)?;
+
+    tokio::time::timeout(timeout, async {
+        let mut buf = vec![0u8; MAX_UDP_ANSWER];
+        loop {
+            let n = socket.recv(&mut buf).await.map_err(io)?;
+            // a late answer to some other query
+            if n < 2 || u16::from_be_bytes([buf[0], buf[1]]) != id {
+                continue;
+            }
+            return parse_txt_answer(&buf[..n]);
+        }
+    })
+    .await
+    .map_err(|_| DnsError::Timeout)?
+}
+
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>, DnsError> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAGS_QUERY.to_be_bytes());
+    // one question, no other records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn parse_txt_answer(answer: &[u8]) -> Result<Vec<String>, DnsError> {
+    let mut reader = Reader(answer);
+    reader.bytes(2)?;
+    let flags = reader.u16()?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(DnsError::Malformed);
+    }
+    if flags & FLAG_TRUNCATED != 0 {
+        return Err(DnsError::Truncated);
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Ok(Vec::new()),
+        rcode => return Err(DnsError::Failed(rcode)),
+    }
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    reader.bytes(4)?;
+
+    for _ in 0..questions {
+        reader.skip_name()?;
+        // type and class
+        reader.bytes(4)?;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        reader.skip_name()?;
+        let record_type = reader.u16()?;
+        // class and ttl
+        reader.bytes(6)?;
+        let data_len = reader.u16()? as usize;
+        let data = reader.bytes(data_len)?;
+        // a CNAME on the way is answered along with the records it points at
+        if record_type != TYPE_TXT {
+            continue;
+        }
+        let mut strings = Reader(data);
+        let mut text = Vec::new();
+        while !strings.is_empty() {
+            let len = strings.u8()? as usize;
+            text.extend_from_slice(strings.bytes(len)?);
+        }
+        records.push(String::from_utf8_lossy(&text).to_string());
+    }
+    Ok(records)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DnsError> {
+        if self.0.len() < len {
+            return Err(DnsError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DnsError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DnsError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Steps over a name, which ends with an empty label or a pointer to an earlier one.
+    fn skip_name(&mut self) -> Result<(), DnsError> {
+        loop {
+            let len = self.u8()?;
+            match len {
+                0 => return Ok(()),
+                len if len & 0xc0 == 0xc0 => {
+                    self.bytes(1)?;
+                    return Ok(());
+                }
+                len => {
+                    self.bytes(len as usize)?;
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An answer to `query` with one TXT record per entry of `records`.
+    fn txt_answer(query: &[u8], records: &[&[&str]]) -> Vec<u8> {
+        let mut answer = query.to_vec();
+        answer[2] |= 0x80;
+        answer[7] = records.len() as u8;
+        for strings in records {
+            // a pointer to the name in the question
+            answer.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            answer.extend_from_slice(&TYPE_TXT.to_be_bytes());
+            answer.extend_from_slice(&CLASS_IN.to_be_bytes());
+            answer.extend_from_slice(&300u32.to_be_bytes());
+            let data: Vec<u8> = strings
+                .iter()
+                .flat_map(|s| std::iter::once(s.len() as u8).chain(s.bytes()))
+                .collect();
+            answer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            answer.extend_from_slice(&data);
+        }
+        answer
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "_c.example.com.").unwrap();
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(
+            &query[HEADER_LEN..],
+            b"\x02_c\x07example\x03com\x00\x00\x10\x00\x01"
+        );
+        assert_eq!(
+            encode_query(1, "a..example.com"),
+            Err(DnsError::InvalidName)
+        );
+    }
+
+    #[test]
+    fn test_parse_txt_answer() {
+        let query = encode_query(7, "_c.example.com").unwrap();
+        let answer = txt_answer(&query, &[&["abc", "def"], &["v=spf1 -all"]]);
+        assert_eq!(
+            parse_txt_answer(&answer),
+            Ok(vec!["abcdef".to_string(), "v=spf1 -all".to_string()])
+        );
+
+        let mut missing = txt_answer(&query, &[]);
+        missing[3] |= RCODE_NAME_ERROR as u8;
+        assert_eq!(parse_txt_answer(&missing), Ok(Vec::new()));
+        let mut failed = txt_answer(&query, &[]);
+        failed[3] |= 2;
+        assert_eq!(parse_txt_answer(&failed), Err(DnsError::Failed(2)));
+        assert_eq!(parse_txt_answer(&query), Err(DnsError::Malformed));
+        assert_eq!(
+            parse_txt_answer(&answer[..answer.len() - 1]),
+            Err(DnsError::Malformed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_txt() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = resolver.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (n, peer) = resolver.recv_from(&mut buf).await.unwrap();
+            let answer = txt_answer(&buf[..n], &[&["token"]]);
+            resolver.send_to(&answer, peer).await.unwrap();
+        });
+        let records = lookup_txt(addr, "_c.example.com", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(records, ["token"]);
+    }
+}
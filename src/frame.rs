@@ -0,0 +1,151 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::codec::{
+    CodecError, ErrorMessage, FRAME_HEADER_LEN, HandshakeAck, decode_header, encode_frame,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    // client -> server: first frame on a connection, see `codec::Handshake`
+    Handshake = 1,
+    // server -> client: handshake accepted, see `codec::HandshakeAck`
+    Ack = 2,
+    // server -> client: the connection is refused, see `codec::ErrorMessage`
+    Error = 3,
+    // server -> client: a new stream, the payload is the first request bytes
+    Open = 4,
+    // both directions: more bytes for an open stream
+    Data = 5,
+    // both directions: the stream is finished and its id can be dropped
+    Close = 6,
+}
+
+impl FrameType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(FrameType::Handshake),
+            2 => Some(FrameType::Ack),
+            3 => Some(FrameType::Error),
+            4 => Some(FrameType::Open),
+            5 => Some(FrameType::Data),
+            6 => Some(FrameType::Close),
+            _ => None,
+        }
+    }
+}
+
+// set on an `Open` frame whose stream is a raw TCP connection rather than an HTTP request
+pub const FLAG_RAW: u8 = 1;
+// set on an `Open` frame for a UDP session, the payload is the peer address and every
+// `Data` frame carries one datagram
+pub const FLAG_DATAGRAM: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(frame_type: FrameType, stream_id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            frame_type,
+            flags: 0,
+            stream_id,
+            payload,
+        }
+    }
+
+    pub fn ack(ack: &HandshakeAck) -> Self {
+        Self::new(FrameType::Ack, 0, ack.encode())
+    }
+
+    pub fn error(error: &ErrorMessage) -> Self {
+        Self::new(FrameType::Error, 0, error.encode())
+    }
+
+    pub fn open(stream_id: u32, payload: Vec<u8>) -> Self {
+        Self::new(FrameType::Open, stream_id, payload)
+    }
+
+    pub fn data(stream_id: u32, payload: Vec<u8>) -> Self {
+        Self::new(FrameType::Data, stream_id, payload)
+    }
+
+    pub fn close(stream_id: u32) -> Self {
+        Self::new(FrameType::Close, stream_id, Vec::new())
+    }
+}
+
+/// Reads one frame, `Ok(None)` means the peer closed the connection between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>, CodecError> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let header = decode_header(&header)?;
+
+    let mut payload = vec![0u8; header.length];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame {
+        frame_type: header.frame_type,
+        flags: header.flags,
+        stream_id: header.stream_id,
+        payload,
+    }))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), CodecError> {
+    writer.write_all(&encode_frame(frame)).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_write_frame() {
+        let frame = Frame::new(FrameType::Data, 7, b"HTTP/1.1 200 OK\r\n\r\n".to_vec());
+        let mut encoded = Vec::new();
+        write_frame(&mut encoded, &frame).await.unwrap();
+
+        let decoded = read_frame(&mut encoded.as_slice()).await.unwrap();
+        assert_eq!(decoded, Some(frame));
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_eof() {
+        let empty: &[u8] = &[];
+        let decoded = read_frame(&mut &empty[..]).await.unwrap();
+        assert_eq!(decoded, None);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_unknown_type() {
+        let mut encoded = encode_frame(&Frame::close(1));
+        encoded[1] = 42;
+        assert!(read_frame(&mut encoded.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_multiple_frames() {
+        let mut encoded = encode_frame(&Frame::open(1, b"a".to_vec()));
+        encoded.extend(encode_frame(&Frame::close(2)));
+        let mut reader = encoded.as_slice();
+
+        let first = read_frame(&mut reader).await.unwrap().unwrap();
+        let second = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(first.stream_id, 1);
+        assert_eq!(second.frame_type, FrameType::Close);
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+}
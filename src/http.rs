@@ -0,0 +1,687 @@
+use std::fmt;
+
+// a head larger than this is refused instead of buffered, unless configured otherwise
+pub const DEFAULT_MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+
+const CONTENT_LENGTH: &str = "Content-Length";
+const CONNECTION: &str = "Connection";
+const HOST: &str = "Host";
+const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+const UPGRADE: &str = "Upgrade";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    HeadTooLarge,
+    TooManyHeaders,
+    InvalidStartLine,
+    InvalidVersion,
+    InvalidStatus,
+    InvalidHeader,
+    InvalidContentLength,
+    // a request with both `Transfer-Encoding` and `Content-Length`
+    ConflictingFraming,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::HeadTooLarge => write!(f, "message head too large"),
+            ParseError::TooManyHeaders => write!(f, "more than {MAX_HEADERS} header fields"),
+            ParseError::InvalidStartLine => write!(f, "malformed start line"),
+            ParseError::InvalidVersion => write!(f, "unsupported HTTP version"),
+            ParseError::InvalidStatus => write!(f, "malformed status code"),
+            ParseError::InvalidHeader => write!(f, "malformed header field"),
+            ParseError::InvalidContentLength => write!(f, "invalid Content-Length"),
+            ParseError::ConflictingFraming => {
+                write!(f, "both Transfer-Encoding and Content-Length")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+
+    fn parse(text: &[u8]) -> Result<Self, ParseError> {
+        match text {
+            b"HTTP/1.0" => Ok(Version::Http10),
+            b"HTTP/1.1" => Ok(Version::Http11),
+            _ => Err(ParseError::InvalidVersion),
+        }
+    }
+}
+
+/// Header fields in the order they arrived, names compare case-insensitively
+/// and a name can appear more than once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Whether any value of a comma separated header such as `Connection` lists `token`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Already validated while parsing, so any value here is a single number.
+    pub fn content_length(&self) -> Option<usize> {
+        self.get(CONTENT_LENGTH)?
+            .split(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /// The `Host` header without its port.
+    pub fn host(&self) -> Option<&str> {
+        let host = self.headers.get(HOST)?.trim();
+        let host = match host.strip_prefix('[') {
+            // IPv6 literal, `[::1]:8080`
+            Some(rest) => rest.split_once(']')?.0,
+            None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+        };
+        (!host.is_empty()).then_some(host)
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.method == "HEAD"
+    }
+
+    /// A request asking to switch protocols, such as a WebSocket handshake.
+    pub fn is_upgrade(&self) -> bool {
+        self.headers.get(UPGRADE).is_some() && self.headers.has_token(CONNECTION, "upgrade")
+    }
+
+    /// Whether the browser expects the connection to stay open after this exchange.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !self.headers.has_token(CONNECTION, "close"),
+            Version::Http10 => self.headers.has_token(CONNECTION, "keep-alive"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub version: Version,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    /// An informational response (`100 Continue`, ...) is followed by another head on the same exchange.
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+
+    pub fn is_switching_protocols(&self) -> bool {
+        self.status == 101
+    }
+}
+
+/// Parses a request head from the start of `buf`, returns the head and its length
+/// including the blank line, or `Ok(None)` while more bytes are needed.
+/// A head longer than `max_head_size` is an error.
+pub fn parse_request(
+    buf: &[u8],
+    max_head_size: usize,
+) -> Result<Option<(RequestHead, usize)>, ParseError> {
+    // empty lines before the request line are allowed and ignored, but count toward the limit
+    let skipped = buf
+        .iter()
+        .take_while(|&&b| b == b'\r' || b == b'\n')
+        .count();
+    if skipped > max_head_size {
+        return Err(ParseError::HeadTooLarge);
+    }
+    let Some((lines, len)) = split_head(&buf[skipped..], max_head_size - skipped)? else {
+        return Ok(None);
+    };
+
+    let mut parts = lines[0].split(|&b| b == b' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::InvalidStartLine);
+    };
+    if method.is_empty() || !method.iter().all(|&b| is_token(b)) {
+        return Err(ParseError::InvalidStartLine);
+    }
+    if target.is_empty() || !target.iter().all(|&b| b > b' ' && b != 0x7f) {
+        return Err(ParseError::InvalidStartLine);
+    }
+
+    let head = RequestHead {
+        method: String::from_utf8_lossy(method).to_string(),
+        target: String::from_utf8_lossy(target).to_string(),
+        version: Version::parse(version)?,
+        headers: parse_headers(&lines[1..])?,
+    };
+    // the upstream could pick the other one and see a different end of the body
+    if head.headers.get(TRANSFER_ENCODING).is_some() && head.headers.get(CONTENT_LENGTH).is_some() {
+        return Err(ParseError::ConflictingFraming);
+    }
+    Ok(Some((head, skipped + len)))
+}
+
+/// Parses a response head from the start of `buf`, same contract as [`parse_request`].
+pub fn parse_response(
+    buf: &[u8],
+    max_head_size: usize,
+) -> Result<Option<(ResponseHead, usize)>, ParseError> {
+    let Some((lines, len)) = split_head(buf, max_head_size)? else {
+        return Ok(None);
+    };
+
+    let mut parts = lines[0].splitn(3, |&b| b == b' ');
+    let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+        return Err(ParseError::InvalidStartLine);
+    };
+    let reason = parts.next().unwrap_or_default();
+    if status.len() != 3 || !status.iter().all(u8::is_ascii_digit) {
+        return Err(ParseError::InvalidStatus);
+    }
+    if reason.iter().any(|&b| is_invalid_value_byte(b)) {
+        return Err(ParseError::InvalidStartLine);
+    }
+
+    let head = ResponseHead {
+        version: Version::parse(version)?,
+        status: str_from_ascii(status)
+            .parse()
+            .map_err(|_| ParseError::InvalidStatus)?,
+        reason: String::from_utf8_lossy(reason).to_string(),
+        headers: parse_headers(&lines[1..])?,
+    };
+    Ok(Some((head, len)))
+}
+
+// the lines of a head and its length in bytes
+type HeadLines<'a> = (Vec<&'a [u8]>, usize);
+
+/// Splits a head into its lines without line endings, stops at the first empty line.
+fn split_head(buf: &[u8], max_head_size: usize) -> Result<Option<HeadLines<'_>>, ParseError> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    loop {
+        let Some(end) = buf[pos..].iter().position(|&b| b == b'\n') else {
+            if buf.len() > max_head_size {
+                return Err(ParseError::HeadTooLarge);
+            }
+            return Ok(None);
+        };
+        let line = &buf[pos..pos + end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        pos += end + 1;
+        if pos > max_head_size {
+            return Err(ParseError::HeadTooLarge);
+        }
+        if line.is_empty() {
+            if lines.is_empty() {
+                return Err(ParseError::InvalidStartLine);
+            }
+            return Ok(Some((lines, pos)));
+        }
+        lines.push(line);
+    }
+}
+
+fn parse_headers(lines: &[&[u8]]) -> Result<Headers, ParseError> {
+    if lines.len() > MAX_HEADERS {
+        return Err(ParseError::TooManyHeaders);
+    }
+    let mut headers = Headers::default();
+    for line in lines {
+        // obsolete line folding is refused, as RFC 9112 allows
+        let colon = line
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or(ParseError::InvalidHeader)?;
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        if name.is_empty() || !name.iter().all(|&b| is_token(b)) {
+            return Err(ParseError::InvalidHeader);
+        }
+        if value.iter().any(|&b| is_invalid_value_byte(b)) {
+            return Err(ParseError::InvalidHeader);
+        }
+        let value = String::from_utf8_lossy(value);
+        headers.append(str_from_ascii(name), value.trim_matches([' ', '\t']));
+    }
+    validate_content_length(&headers)?;
+    Ok(headers)
+}
+
+/// Every `Content-Length` value must be the same number, anything else
+/// could make the tunnel and the browser disagree on where the body ends.
+fn validate_content_length(headers: &Headers) -> Result<(), ParseError> {
+    let mut length = None;
+    for item in headers.get_all(CONTENT_LENGTH).flat_map(|v| v.split(',')) {
+        let item = item.trim();
+        if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        let value: usize = item.parse().map_err(|_| ParseError::InvalidContentLength)?;
+        if length.is_some_and(|length| length != value) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        length = Some(value);
+    }
+    Ok(())
+}
+
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn is_invalid_value_byte(b: u8) -> bool {
+    (b < b' ' && b != b'\t') || b == 0x7f
+}
+
+// only called on bytes already checked to be ASCII
+fn str_from_ascii(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_HEAD_SIZE: usize = DEFAULT_MAX_HEAD_SIZE;
+
+    fn request(text: &str) -> RequestHead {
+        parse_request(text.as_bytes(), MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let text = "GET /favicon.ico HTTP/1.1\r\nHost: test.example.com\r\nAccept: */*\r\n\r\nbody";
+        let (head, len) = parse_request(text.as_bytes(), MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, text.len() - 4);
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.target, "/favicon.ico");
+        assert_eq!(head.version, Version::Http11);
+        assert_eq!(head.headers.get("accept"), Some("*/*"));
+        assert_eq!(head.host(), Some("test.example.com"));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let text = "HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\n\r\nnop";
+        let (head, len) = parse_response(text.as_bytes(), MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, text.len() - 3);
+        assert_eq!(head.status, 404);
+        assert_eq!(head.reason, "Not Found");
+        assert_eq!(head.headers.content_length(), Some(3));
+
+        let (head, _) = parse_response(b"HTTP/1.0 200\r\n\r\n", MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (head.version, head.status, head.reason.as_str()),
+            (Version::Http10, 200, "")
+        );
+    }
+
+    #[test]
+    fn test_incomplete_head() {
+        assert_eq!(parse_request(b"", MAX_HEAD_SIZE), Ok(None));
+        assert_eq!(
+            parse_request(b"GET / HTTP/1.1\r\nHost: a.b.c\r\n", MAX_HEAD_SIZE),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\n\r", MAX_HEAD_SIZE),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_headers_case_insensitive_multi_value() {
+        let head = request(
+            "GET / HTTP/1.1\r\nset-cookie: a=1\r\nHost: x.y\r\nSet-Cookie: b=2\r\nCONNECTION: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n",
+        );
+        assert_eq!(head.headers.get("SET-COOKIE"), Some("a=1"));
+        assert_eq!(
+            head.headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+            vec!["a=1", "b=2"]
+        );
+        assert!(head.headers.has_token("connection", "upgrade"));
+        assert!(head.is_upgrade());
+        assert!(
+            !request(
+                "GET / HTTP/1.1\r\nUpgrade-Insecure-Requests: 1\r\nConnection: upgrade\r\n\r\n"
+            )
+            .is_upgrade()
+        );
+    }
+
+    #[test]
+    fn test_host_with_port() {
+        assert_eq!(
+            request("GET / HTTP/1.1\r\nHost: a.b:8080\r\n\r\n").host(),
+            Some("a.b")
+        );
+        assert_eq!(
+            request("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n").host(),
+            Some("::1")
+        );
+        assert_eq!(
+            request("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").host(),
+            Some("localhost")
+        );
+        assert_eq!(request("GET / HTTP/1.1\r\n\r\n").host(), None);
+    }
+
+    #[test]
+    fn test_value_keeps_colons() {
+        let head =
+            request("GET / HTTP/1.1\r\nX-Forwarded-For: ::1\r\nReferer: http://a.b:80/x\r\n\r\n");
+        assert_eq!(head.headers.get("x-forwarded-for"), Some("::1"));
+        assert_eq!(head.headers.get("referer"), Some("http://a.b:80/x"));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        assert!(request("GET / HTTP/1.1\r\n\r\n").keep_alive());
+        assert!(!request("GET / HTTP/1.1\r\nConnection: close\r\n\r\n").keep_alive());
+        assert!(!request("GET / HTTP/1.0\r\n\r\n").keep_alive());
+        assert!(request("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").keep_alive());
+    }
+
+    #[test]
+    fn test_content_length_validation() {
+        assert_eq!(
+            request("POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\n")
+                .headers
+                .content_length(),
+            Some(5)
+        );
+        for bad in ["abc", "", "5, 6", "-1", "+5", "99999999999999999999999"] {
+            let text = format!("POST / HTTP/1.1\r\nContent-Length: {bad}\r\n\r\n");
+            assert_eq!(
+                parse_request(text.as_bytes(), MAX_HEAD_SIZE),
+                Err(ParseError::InvalidContentLength),
+                "{bad:?}"
+            );
+        }
+        assert_eq!(
+            parse_request(
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+                MAX_HEAD_SIZE
+            ),
+            Err(ParseError::InvalidContentLength)
+        );
+    }
+
+    #[test]
+    fn test_conflicting_framing() {
+        for text in [
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n",
+            "POST / HTTP/1.1\r\ncontent-length: 3\r\ntransfer-encoding: gzip, chunked\r\n\r\n",
+        ] {
+            assert_eq!(
+                parse_request(text.as_bytes(), MAX_HEAD_SIZE),
+                Err(ParseError::ConflictingFraming),
+                "{text:?}"
+            );
+        }
+        // a response is only passed on, the browser sorts it out
+        let text = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n";
+        assert!(parse_response(text, MAX_HEAD_SIZE).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let cases: &[(&[u8], ParseError)] = &[
+            (b"GET /\r\n\r\n", ParseError::InvalidStartLine),
+            (b"GET  / HTTP/1.1\r\n\r\n", ParseError::InvalidStartLine),
+            (
+                b"GET / HTTP/1.1 extra\r\n\r\n",
+                ParseError::InvalidStartLine,
+            ),
+            (b"G(T / HTTP/1.1\r\n\r\n", ParseError::InvalidStartLine),
+            (b"GET / HTTP/2.0\r\n\r\n", ParseError::InvalidVersion),
+            (
+                b"GET / HTTP/1.1\r\nNoColon\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+            (b"GET / HTTP/1.1\r\n: x\r\n\r\n", ParseError::InvalidHeader),
+            (
+                b"GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nA: b\0c\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nA: b\rc\r\n\r\n",
+                ParseError::InvalidHeader,
+            ),
+        ];
+        for (input, error) in cases {
+            assert_eq!(
+                parse_request(input, MAX_HEAD_SIZE).map(|_| ()),
+                Err(error.clone()),
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_responses() {
+        assert_eq!(
+            parse_response(b"HTTP/1.1 20 OK\r\n\r\n", MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::InvalidStatus)
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.1 abc OK\r\n\r\n", MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::InvalidStatus)
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.1\r\n\r\n", MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::InvalidStartLine)
+        );
+        assert_eq!(
+            parse_response(b"SPDY/3 200 OK\r\n\r\n", MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::InvalidVersion)
+        );
+        assert_eq!(
+            parse_response(
+                b"CLIENT_ERROR:ERR_CONNECTION_REFUSED\r\n\r\n",
+                MAX_HEAD_SIZE
+            )
+            .map(|_| ()),
+            Err(ParseError::InvalidStartLine)
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut text = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        text.resize(MAX_HEAD_SIZE + 1, b'a');
+        assert_eq!(
+            parse_request(&text, MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::HeadTooLarge)
+        );
+
+        let many = "A: b\r\n".repeat(MAX_HEADERS + 1);
+        let text = format!("GET / HTTP/1.1\r\n{many}\r\n");
+        assert_eq!(
+            parse_request(text.as_bytes(), MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::TooManyHeaders)
+        );
+    }
+
+    #[test]
+    fn test_leading_empty_lines_and_bare_lf() {
+        let (head, len) = parse_request(b"\r\n\r\nGET / HTTP/1.1\nHost: a.b\n\n", MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(head.host(), Some("a.b"));
+        assert_eq!(len, 30);
+
+        let mut text = b"\r\n".repeat(MAX_HEAD_SIZE / 2);
+        text.push(b'\r');
+        assert_eq!(
+            parse_request(&text, MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::HeadTooLarge)
+        );
+        text.truncate(MAX_HEAD_SIZE - 10);
+        text.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            parse_request(&text, MAX_HEAD_SIZE).map(|_| ()),
+            Err(ParseError::HeadTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_interim_and_switching() {
+        let head = |text: &str| {
+            parse_response(text.as_bytes(), MAX_HEAD_SIZE)
+                .unwrap()
+                .unwrap()
+                .0
+        };
+        assert!(head("HTTP/1.1 100 Continue\r\n\r\n").is_interim());
+        assert!(!head("HTTP/1.1 101 Switching Protocols\r\n\r\n").is_interim());
+        assert!(head("HTTP/1.1 101 Switching Protocols\r\n\r\n").is_switching_protocols());
+        assert!(!head("HTTP/1.1 200 OK\r\n\r\n").is_interim());
+    }
+
+    // small deterministic generator so failures can be replayed
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+    }
+
+    #[test]
+    fn fuzz_truncated_input_is_incomplete() {
+        let text =
+            b"POST /upload?x=1 HTTP/1.1\r\nHost: a.example.com:8080\r\nContent-Length: 4\r\n\r\n";
+        for cut in 0..text.len() {
+            assert_eq!(
+                parse_request(&text[..cut], MAX_HEAD_SIZE),
+                Ok(None),
+                "cut at {cut}"
+            );
+        }
+        assert!(parse_request(text, MAX_HEAD_SIZE).unwrap().is_some());
+    }
+
+    #[test]
+    fn fuzz_mutated_input_never_panics() {
+        let seeds: [&[u8]; 2] = [
+            b"GET /a HTTP/1.1\r\nHost: a.example.com\r\nConnection: Upgrade\r\nUpgrade: ws\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 12\r\n\r\n",
+        ];
+        let alphabet = b" :\r\n\t\0\x7f\xffaZ0-,;[]";
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..5000 {
+            let mut input = seeds[(rng.next() % 2) as usize].to_vec();
+            for _ in 0..=(rng.next() % 4) {
+                let pos = (rng.next() as usize) % input.len();
+                let byte = alphabet[(rng.next() as usize) % alphabet.len()];
+                match rng.next() % 3 {
+                    0 => input[pos] = byte,
+                    1 => input.insert(pos, byte),
+                    _ => {
+                        input.remove(pos);
+                    }
+                }
+            }
+            for parsed in [
+                parse_request(&input, MAX_HEAD_SIZE).map(|head| head.map(|(_, len)| len)),
+                parse_response(&input, MAX_HEAD_SIZE).map(|head| head.map(|(_, len)| len)),
+            ] {
+                if let Ok(Some(len)) = parsed {
+                    assert!(len <= input.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes_never_panic() {
+        let mut rng = Lcg(42);
+        for _ in 0..2000 {
+            let len = (rng.next() % 200) as usize;
+            let input: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            let _ = parse_request(&input, MAX_HEAD_SIZE);
+            let _ = parse_response(&input, MAX_HEAD_SIZE);
+        }
+    }
+}
@@ -1,179 +1,596 @@
-use rand::Rng;
 use std::str;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::UnboundedReceiver;
-
-use crate::request::HttpRequest;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio_rustls::TlsAcceptor;
+
+use crate::access_log::{AccessEntry, AccessLogFormat};
+use crate::body::{BodyFraming, BodyTracker};
+use crate::capture::Recorder;
+use crate::config::{Limits, Timeouts};
+use crate::http::{ParseError, RequestHead, parse_request, parse_response};
+use crate::metrics::Metrics;
+use crate::path_prefix;
 use crate::response::HttpResponse;
+use crate::routing::{Route, Router};
 use crate::shared::SharedState;
-use crate::shared::TicketRequestHttp;
+use crate::shared::{ResponseEvent, StreamMode, TicketRequestHttp, stream_name};
 
 use tokio::sync::mpsc;
 
 pub struct HttpServer {
     listener: TcpListener,
     shared_state: SharedState,
+    // set when the listener terminates TLS itself
+    tls: Option<TlsAcceptor>,
+    router: Router,
+    limits: Limits,
+    timeouts: Timeouts,
+    access_log: AccessLogFormat,
 }
 
-const TWO_DELIMETER_BYTES: &[u8] = b"\r\n\r\n";
 const CRLF: &[u8] = b"\r\n";
 
 const X_REAL_IP: &str = "X-Real-IP";
-const CONNECTION: &str = "Connection";
+const HOST: &str = "Host";
 
 impl HttpServer {
     pub async fn new(
-        addr: &str,
+        addr: SocketAddr,
         shared_state: SharedState,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        router: Router,
+        limits: Limits,
+        timeouts: Timeouts,
+        access_log: AccessLogFormat,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(addr).await?;
         Ok(HttpServer {
             listener,
             shared_state,
+            tls: tls_config.map(TlsAcceptor::from),
+            router,
+            limits,
+            timeouts,
+            access_log,
         })
     }
 
     pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
         loop {
-            let (socket, _addr) = self.listener.accept().await?;
+            let (socket, addr) = self.listener.accept().await?;
 
             // Spawn a new task for each connection
             let shared_state = self.shared_state.clone();
+            let tls = self.tls.clone();
+            let router = self.router.clone();
+            let limits = self.limits.clone();
+            let timeouts = self.timeouts.clone();
+            let access_log = self.access_log;
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(socket, shared_state).await {
+                let result = match tls {
+                    Some(acceptor) => match acceptor.accept(socket).await {
+                        Ok(stream) => {
+                            Self::handle_connection(
+                                stream,
+                                addr,
+                                shared_state,
+                                router,
+                                limits,
+                                timeouts,
+                                access_log,
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            tracing::debug!("TLS handshake with {addr} failed: {e}");
+                            return;
+                        }
+                    },
+                    None => {
+                        Self::handle_connection(
+                            socket,
+                            addr,
+                            shared_state,
+                            router,
+                            limits,
+                            timeouts,
+                            access_log,
+                        )
+                        .await
+                    }
+                };
+                if let Err(e) = result {
                     eprintln!("Error handling HTTP connection: {e}");
                 }
             });
         }
     }
 
-    async fn handle_connection(
-        mut stream: TcpStream,
+    async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
+        addr: SocketAddr,
         shared_state: SharedState,
+        router: Router,
+        limits: Limits,
+        timeouts: Timeouts,
+        access_log: AccessLogFormat,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        // bytes read past the end of the current request, the start of the next one
+        let mut buffer: Vec<u8> = Vec::new();
         loop {
-            let status_text;
-
-            let mut total_data = get_rawdata_delimiter(&mut stream).await.unwrap();
-
-            let header = total_data.windows(4).position(|w| w == TWO_DELIMETER_BYTES);
-            let headers_end = match header {
-                Some(value) => value + 4,
-                None => {
-                    break;
-                }
+            let Some((request, head_len)) =
+                read_head(&mut stream, &mut buffer, &limits, &timeouts).await?
+            else {
+                break;
             };
 
-            let headers_str = str::from_utf8(&total_data[..headers_end - 4])?.to_string();
-
-            let ip = HttpRequest::parse_check_value_header(headers_str.clone(), X_REAL_IP)
-                .unwrap_or("".to_string());
-            let req_txt = HttpRequest::parse_content_request_format(headers_str.clone());
-            status_text = format!("{ip}: {req_txt}");
-
-            let content_length = HttpRequest::parse_content_length(headers_str.clone());
-            if let Some(body_length) = content_length {
-                let body_data_received = total_data.len() - headers_end;
-                let remaining_body = body_length - body_data_received;
-                if remaining_body > 0 {
-                    let mut body_buf = vec![0u8; remaining_body];
-                    let mut bytes_read = 0;
-
-                    while bytes_read < remaining_body {
-                        let n = stream.read(&mut body_buf[bytes_read..]).await?;
-                        if n == 0 {
-                            return Err("Unexpected EOF while reading body".into());
-                        }
-                        bytes_read += n;
-                    }
-                    total_data.extend_from_slice(&body_buf);
+            // behind nginx the browser's address comes in `X-Real-IP`
+            let remote_addr = request
+                .headers
+                .get(X_REAL_IP)
+                .map_or_else(|| addr.ip().to_string(), str::to_string);
+            let mut exchange = Exchange {
+                access: AccessEntry::new(&request, &remote_addr),
+                capture: None,
+            };
+            let metrics = &shared_state.metrics;
+
+            let custom = request
+                .host()
+                .and_then(|host| shared_state.custom_domains.lookup(host));
+            let route = match custom {
+                Some(client_id) => Route::Tunnel(client_id),
+                None => router.route(&request),
+            };
+            let (client_id, prefix) = match route {
+                Route::Tunnel(name) => (name, None),
+                Route::Prefixed { tunnel, prefix } => (tunnel, Some(prefix)),
+                Route::Redirect(location) => {
+                    return reply(
+                        &mut stream,
+                        HttpResponse::moved_permanently(&location),
+                        metrics,
+                        &mut exchange,
+                        access_log,
+                    )
+                    .await;
                 }
-            }
-
-            let response_data: Vec<u8> = total_data.to_vec();
-            let request_str = str::from_utf8(&response_data)?;
+                Route::NoTunnel => {
+                    return reply(
+                        &mut stream,
+                        HttpResponse::not_found(),
+                        metrics,
+                        &mut exchange,
+                        access_log,
+                    )
+                    .await;
+                }
+                Route::Misdirected => {
+                    return reply(
+                        &mut stream,
+                        HttpResponse::misdirected_request(),
+                        metrics,
+                        &mut exchange,
+                        access_log,
+                    )
+                    .await;
+                }
+            };
 
-            let client_id = HttpRequest::get_subdomain(request_str);
-            if client_id == "" {
-                let response = HttpResponse::not_found().to_string();
-                stream.write_all(response.as_bytes()).await?;
-                stream.flush().await?;
-                return Ok(());
+            exchange.access.tunnel = Some(client_id.clone());
+            exchange.capture = shared_state
+                .captures
+                .recorder(&client_id, &remote_addr, &request);
+            let mut body = BodyTracker::new(BodyFraming::for_request(&request));
+            let rest = buffer.split_off(head_len);
+            let mut data = std::mem::take(&mut buffer);
+            if let Some(prefix) = &prefix {
+                data = path_prefix::strip_request_prefix(&data, prefix);
             }
+            let used = body.feed(&rest);
+            exchange.access.request_bytes = head_len as u64;
+            exchange.request_body(&rest[..used]);
+            data.extend_from_slice(&rest[..used]);
+            buffer.extend_from_slice(&rest[used..]);
+
+            let upgrade = request.is_upgrade();
+            let (mut tx_body, rx_body) = if body.is_complete() && !upgrade {
+                (None, None)
+            } else {
+                let (tx_body, rx_body) = mpsc::channel::<Vec<u8>>(limits.body_channel_capacity);
+                (Some(tx_body), Some(rx_body))
+            };
 
-            let trx_id = generate_trx_id(client_id.to_string());
+            let trx_id = stream_name(&client_id, "tx");
 
             let ticket = TicketRequestHttp {
-                name: format!("{trx_id}"),
-                data: total_data,
+                name: trx_id.clone(),
+                mode: if upgrade {
+                    StreamMode::Upgrade
+                } else {
+                    StreamMode::Http
+                },
+                head_request: request.is_head(),
+                data,
+                body: rx_body,
             };
 
-            let (tx_http, rx_http) = mpsc::unbounded_channel::<Vec<u8>>();
+            let (tx_http, rx_http) =
+                mpsc::channel::<ResponseEvent>(limits.response_channel_capacity);
 
-            shared_state
-                .register_http_client(ticket.name.clone(), tx_http)
-                .await;
+            // the entry goes away with this guard, whichever way the request ends
+            let _pending = shared_state.register_http_client(trx_id.clone(), tx_http);
 
             if !shared_state
                 .send_to_tcp_client(client_id.as_str(), ticket)
                 .await
             {
-                return Err("sending fails".into());
+                tracing::info!("no tunnel named {client_id}");
+                return reply(
+                    &mut stream,
+                    HttpResponse::not_found(),
+                    metrics,
+                    &mut exchange,
+                    access_log,
+                )
+                .await;
             }
 
-            // waiting for response from TCP client
-            wait_for_tcp_response(rx_http, &mut stream, status_text).await;
-
-            if let Some(conn_type) = HttpRequest::parse_check_value_header(headers_str, CONNECTION)
+            let mut body_sent = true;
+            if let Some(tx_body) = &tx_body
+                && !body.is_complete()
             {
-                if conn_type == "close" {
+                let outcome = forward_request_body(
+                    &mut stream,
+                    &mut body,
+                    tx_body,
+                    &mut buffer,
+                    &limits,
+                    &timeouts,
+                    &mut exchange,
+                )
+                .await?;
+                match outcome {
+                    BodyOutcome::Complete => {}
+                    BodyOutcome::TunnelClosed => body_sent = false,
+                    BodyOutcome::TimedOut => {
+                        tracing::info!("request body for {trx_id} timed out");
+                        return reply(
+                            &mut stream,
+                            HttpResponse::gateway_timeout(),
+                            metrics,
+                            &mut exchange,
+                            access_log,
+                        )
+                        .await;
+                    }
+                }
+            }
+            if !upgrade {
+                // closing the body channel lets the tunnel know the request is done
+                tx_body = None;
+            }
+
+            // a slow upload is up to `body_read_secs`, the tunnel's clock starts once it is in
+            let deadlines = Deadlines::new(&timeouts);
+            // waiting for response from TCP client
+            let outcome = wait_for_tcp_response(
+                rx_http,
+                &mut stream,
+                &request,
+                prefix.as_deref(),
+                metrics,
+                &deadlines,
+                &mut exchange,
+            )
+            .await?;
+            let keep_alive = match outcome {
+                ResponseOutcome::TimedOut => {
+                    // the response channel is gone, the tunnel closes the stream on its side
+                    tracing::info!("response for {trx_id} timed out");
+                    exchange.finish(access_log);
                     break;
                 }
+                ResponseOutcome::Done { keep_alive } => {
+                    exchange.finish(access_log);
+                    keep_alive
+                }
+                ResponseOutcome::Upgraded(rx_http) => {
+                    if let Some(tx_body) = tx_body {
+                        let leftover = std::mem::take(&mut buffer);
+                        pipe_upgraded(
+                            &mut stream,
+                            rx_http,
+                            tx_body,
+                            leftover,
+                            limits.read_chunk_size,
+                        )
+                        .await?;
+                    }
+                    // logged when the upgraded connection ends, the duration covers all of it
+                    exchange.finish(access_log);
+                    break;
+                }
+            };
+
+            if !body_sent || !keep_alive || !request.keep_alive() {
+                break;
             }
         }
         Ok(())
     }
 }
-async fn wait_for_tcp_response(
-    mut rx_http: UnboundedReceiver<Vec<u8>>,
-    stream: &mut TcpStream,
-    status_text: String,
-) {
-    let status_resp: String;
-    match rx_http.recv().await {
-        Some(value) => {
-            if !value.is_empty() {
-                let header = value.windows(2).position(|w| w == CRLF).unwrap();
-                let header_text = String::from_utf8_lossy(&value[0..header]);
-                status_resp = parse_response_header(header_text.to_string());
-
-                if let Some(v) = check_client_app_error(status_resp.clone()) {
-                    stream.write_all(&v).await.unwrap();
-                } else {
-                    stream.write_all(&value).await.unwrap();
+
+enum BodyOutcome {
+    Complete,
+    // the tunnel stopped taking the body
+    TunnelClosed,
+    // the browser went quiet for longer than `body_read_secs`
+    TimedOut,
+}
+
+/// Streams the rest of the request body into the tunnel.
+async fn forward_request_body<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    body: &mut BodyTracker,
+    tx_body: &mpsc::Sender<Vec<u8>>,
+    leftover: &mut Vec<u8>,
+    limits: &Limits,
+    timeouts: &Timeouts,
+    exchange: &mut Exchange,
+) -> Result<BodyOutcome, Box<dyn std::error::Error>> {
+    let read_timeout = Duration::from_secs(timeouts.body_read_secs);
+    let mut buf = vec![0u8; limits.read_chunk_size];
+    while !body.is_complete() {
+        if body.is_invalid() {
+            return Err("Malformed chunked request body".into());
+        }
+        let Ok(n) = tokio::time::timeout(read_timeout, stream.read(&mut buf)).await else {
+            return Ok(BodyOutcome::TimedOut);
+        };
+        let n = n?;
+        if n == 0 {
+            return Err("Unexpected EOF while reading body".into());
+        }
+        let used = body.feed(&buf[..n]);
+        exchange.request_body(&buf[..used]);
+        leftover.extend_from_slice(&buf[used..n]);
+        if tx_body.send(buf[..used].to_vec()).await.is_err() {
+            return Ok(BodyOutcome::TunnelClosed);
+        }
+    }
+    Ok(BodyOutcome::Complete)
+}
+
+/// What is kept of one request: its access log entry and, when capturing is on, its bytes.
+struct Exchange {
+    access: AccessEntry,
+    capture: Option<Recorder>,
+}
+
+impl Exchange {
+    fn request_body(&mut self, bytes: &[u8]) {
+        self.access.request_bytes += bytes.len() as u64;
+        if let Some(capture) = &mut self.capture {
+            capture.request_body(bytes);
+        }
+    }
+
+    // everything written to the browser goes through here
+    fn response(&mut self, bytes: &[u8]) {
+        self.access.response_bytes += bytes.len() as u64;
+        if let Some(capture) = &mut self.capture {
+            capture.response(bytes);
+        }
+    }
+
+    fn finish(&mut self, format: AccessLogFormat) {
+        self.access.finish(format);
+        if let Some(capture) = self.capture.take() {
+            capture.finish();
+        }
+    }
+}
+
+enum ResponseOutcome {
+    // the response is complete, `keep_alive` tells whether the connection can be reused
+    Done { keep_alive: bool },
+    // the tunnel answered `101 Switching Protocols`, the connection now carries raw bytes
+    Upgraded(mpsc::Receiver<ResponseEvent>),
+    // a deadline passed, the browser got a 504 unless the response had already started
+    TimedOut,
+}
+
+/// When a request went into the tunnel and how long its response may take.
+struct Deadlines {
+    sent_at: Instant,
+    first_byte: Duration,
+    response: Option<Duration>,
+}
+
+impl Deadlines {
+    fn new(timeouts: &Timeouts) -> Self {
+        Self {
+            sent_at: Instant::now(),
+            first_byte: Duration::from_secs(timeouts.first_byte_secs),
+            response: timeouts.response_secs.map(Duration::from_secs),
+        }
+    }
+
+    // the first byte must come in before the whole response is due
+    fn next(&self, waiting_for_first_byte: bool) -> Option<Instant> {
+        let response = self.response.map(|limit| self.sent_at + limit);
+        if waiting_for_first_byte {
+            let first_byte = self.sent_at + self.first_byte;
+            Some(response.map_or(first_byte, |response| response.min(first_byte)))
+        } else {
+            response
+        }
+    }
+}
+
+/// Writes the response to the browser as it streams in.
+async fn wait_for_tcp_response<S: AsyncRead + AsyncWrite + Unpin>(
+    mut rx_http: mpsc::Receiver<ResponseEvent>,
+    stream: &mut S,
+    request: &RequestHead,
+    prefix: Option<&str>,
+    metrics: &Metrics,
+    deadlines: &Deadlines,
+    exchange: &mut Exchange,
+) -> Result<ResponseOutcome, Box<dyn std::error::Error>> {
+    let mut first_byte = true;
+    let mut started = false;
+    let mut replaced = false;
+    let mut keep_alive = true;
+
+    loop {
+        let event = match deadlines.next(first_byte) {
+            Some(deadline) => {
+                match tokio::time::timeout_at(deadline.into(), rx_http.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        // once the head is out there is no way to tell the browser, only to close
+                        if !started && !replaced {
+                            metrics.record_status(504);
+                            let response = HttpResponse::gateway_timeout().to_string();
+                            exchange.access.status = 504;
+                            exchange.response(response.as_bytes());
+                            stream.write_all(response.as_bytes()).await?;
+                            stream.flush().await?;
+                        }
+                        return Ok(ResponseOutcome::TimedOut);
+                    }
                 }
-            } else {
-                status_resp = "404 Not Found".to_string();
-                let response = HttpResponse::not_found().to_string();
-                stream.write_all(response.as_bytes()).await.unwrap();
             }
-        }
-        None => {
-            status_resp = "".to_string();
-            let response = HttpResponse::service_unavailable().to_string();
-            stream.write_all(response.as_bytes()).await.unwrap();
+            None => rx_http.recv().await,
+        };
+        match event {
+            Some(ResponseEvent::Data(mut value)) => {
+                if replaced {
+                    continue;
+                }
+                if first_byte {
+                    first_byte = false;
+                    metrics.record_first_byte(deadlines.sent_at.elapsed());
+                }
+                if !started {
+                    let header = value
+                        .windows(2)
+                        .position(|w| w == CRLF)
+                        .unwrap_or(value.len());
+                    let header_text = String::from_utf8_lossy(&value[0..header]);
+                    let status_resp = parse_response_header(header_text.to_string());
+
+                    if let Some(v) = check_client_app_error(status_resp) {
+                        metrics.record_status(503);
+                        exchange.access.status = 503;
+                        exchange.response(&v);
+                        stream.write_all(&v).await?;
+                        replaced = true;
+                        keep_alive = false;
+                        continue;
+                    }
+
+                    // the tunnel hands over the head on its own, anything unparsable came from an old client
+                    if let Ok(Some((response, head_len))) = parse_response(&value, value.len()) {
+                        if let Some(prefix) = prefix {
+                            let host = request.headers.get(HOST);
+                            let mut head =
+                                path_prefix::prefix_response_head(&value[..head_len], prefix, host);
+                            head.extend_from_slice(&value[head_len..]);
+                            value = head;
+                        }
+                        if request.is_upgrade() && response.is_switching_protocols() {
+                            metrics.record_status(response.status);
+                            exchange.access.status = response.status;
+                            exchange.response(&value);
+                            stream.write_all(&value).await?;
+                            stream.flush().await?;
+                            return Ok(ResponseOutcome::Upgraded(rx_http));
+                        }
+                        // an interim response is followed by the real head
+                        started = !response.is_interim();
+                        if started {
+                            metrics.record_status(response.status);
+                            exchange.access.status = response.status;
+                        }
+                        let framing = BodyFraming::for_response(&response, request.is_head());
+                        keep_alive = framing != BodyFraming::UntilClose;
+                    } else {
+                        started = true;
+                        keep_alive = false;
+                    }
+                }
+                exchange.response(&value);
+                stream.write_all(&value).await?;
+                // event streams and long polls must reach the browser as each chunk arrives
+                stream.flush().await?;
+            }
+            Some(ResponseEvent::End) => break,
+            Some(ResponseEvent::Abort) | None => {
+                if !started {
+                    metrics.record_status(503);
+                    let response = HttpResponse::service_unavailable().to_string();
+                    exchange.access.status = 503;
+                    exchange.response(response.as_bytes());
+                    stream.write_all(response.as_bytes()).await?;
+                }
+                keep_alive = false;
+                break;
+            }
         }
     }
-    stream.flush().await.unwrap();
-    tracing::info!("{} {}", status_text, status_resp);
+    stream.flush().await?;
+    Ok(ResponseOutcome::Done { keep_alive })
 }
 
-fn generate_trx_id(client_id: String) -> String {
-    let mut rng = rand::rng();
-    let tx_id: u32 = rng.random_range(1000..10000); // 8-digit number
-    format!("{}_tx-{:4}", client_id, tx_id)
+/// Pipes raw bytes both ways after a protocol switch until the tunnel ends the stream.
+/// Once `stream` stops sending, the tunnel still gets to answer.
+pub async fn pipe_upgraded<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut rx_http: mpsc::Receiver<ResponseEvent>,
+    tx_body: mpsc::Sender<Vec<u8>>,
+    leftover: Vec<u8>,
+    read_chunk_size: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !leftover.is_empty() && tx_body.send(leftover).await.is_err() {
+        return Ok(());
+    }
+    // dropped on a half-close, which closes the write direction of the stream
+    let mut tx_body = Some(tx_body);
+    let mut buf = vec![0u8; read_chunk_size];
+    loop {
+        select! {
+            n = stream.read(&mut buf), if tx_body.is_some() => {
+                let n = n?;
+                if n == 0 {
+                    tx_body = None;
+                    continue;
+                }
+                if let Some(tx_body) = &tx_body
+                    && tx_body.send(buf[..n].to_vec()).await.is_err()
+                {
+                    break;
+                }
+            },
+            event = rx_http.recv() => {
+                match event {
+                    Some(ResponseEvent::Data(bytes)) => {
+                        stream.write_all(&bytes).await?;
+                        stream.flush().await?;
+                    },
+                    _ => break,
+                }
+            },
+        }
+    }
+    Ok(())
 }
 
 fn parse_response_header(headers: String) -> String {
@@ -197,33 +614,72 @@ fn check_client_app_error(status_resp: String) -> Option<Vec<u8>> {
     }
 }
 
-async fn get_rawdata_delimiter(stream: &mut TcpStream) -> Option<Vec<u8>> {
-    let mut buf = vec![0u8; 4096]; // Initial capacity
-    let mut total_data: Vec<u8> = Vec::new();
+/// Reads until `buffer` holds a complete request head, `Ok(None)` means the browser closed the connection.
+async fn read_head<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    limits: &Limits,
+    timeouts: &Timeouts,
+) -> Result<Option<(RequestHead, usize)>, Box<dyn std::error::Error>> {
+    let deadline = Instant::now() + Duration::from_secs(timeouts.header_read_secs);
+    let mut buf = vec![0u8; limits.read_chunk_size];
     loop {
-        let n = stream.read(&mut buf).await.unwrap();
-        if n == 0 {
-            break; // EOF
+        match parse_request(buffer, limits.max_head_size) {
+            Ok(Some(parsed)) => return Ok(Some(parsed)),
+            Ok(None) => {}
+            Err(e) => {
+                let response = match e {
+                    ParseError::HeadTooLarge | ParseError::TooManyHeaders => {
+                        HttpResponse::header_fields_too_large()
+                    }
+                    _ => HttpResponse::bad_request(),
+                };
+                stream.write_all(response.to_string().as_bytes()).await?;
+                stream.flush().await?;
+                return Err(e.into());
+            }
         }
-        total_data.extend_from_slice(&buf[..n]);
-
-        if total_data.windows(4).any(|w| w == TWO_DELIMETER_BYTES) {
-            break;
+        let n = match tokio::time::timeout_at(deadline.into(), stream.read(&mut buf)).await {
+            Ok(n) => n?,
+            // an idle keep-alive connection, nothing to answer
+            Err(_) if buffer.is_empty() => return Ok(None),
+            Err(_) => {
+                let response = HttpResponse::gateway_timeout();
+                stream.write_all(response.to_string().as_bytes()).await?;
+                stream.flush().await?;
+                return Err("timed out reading the request head".into());
+            }
+        };
+        if n == 0 {
+            return Ok(None); // EOF
         }
+        buffer.extend_from_slice(&buf[..n]);
     }
-    Some(total_data)
+}
+
+/// Answers with one of the server's own pages and ends the connection.
+async fn reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: HttpResponse,
+    metrics: &Metrics,
+    exchange: &mut Exchange,
+    format: AccessLogFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = response.status_code();
+    metrics.record_status(status);
+    let response = response.to_string();
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    exchange.access.status = status;
+    exchange.response(response.as_bytes());
+    exchange.finish(format);
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_generate_trx_id() {
-        let client_id = "300".to_string();
-        let id = generate_trx_id(client_id);
-        assert_eq!(id.len(), 11); //client_tx-random(4)
-    }
     #[test]
     fn test_response_header() {
         let headers = "HTTP/1.1 200 OK".to_string();
@@ -249,4 +705,166 @@ mod tests {
         let result = check_client_app_error(error_status);
         assert!(result.is_none());
     }
+
+    #[test]
+    fn test_deadlines() {
+        let timeouts = Timeouts {
+            first_byte_secs: 10,
+            response_secs: Some(5),
+            ..Timeouts::default()
+        };
+        let deadlines = Deadlines::new(&timeouts);
+        let sent_at = deadlines.sent_at;
+        assert_eq!(deadlines.next(true), Some(sent_at + Duration::from_secs(5)));
+        assert_eq!(
+            deadlines.next(false),
+            Some(sent_at + Duration::from_secs(5))
+        );
+
+        let deadlines = Deadlines::new(&Timeouts::default());
+        let sent_at = deadlines.sent_at;
+        assert_eq!(
+            deadlines.next(true),
+            Some(sent_at + Duration::from_secs(60))
+        );
+        assert_eq!(deadlines.next(false), None);
+    }
+
+    #[tokio::test]
+    async fn test_first_byte_timeout() {
+        let (request, _) =
+            parse_request(b"GET / HTTP/1.1\r\nHost: myapp.example.com\r\n\r\n", 1024)
+                .unwrap()
+                .unwrap();
+        let mut exchange = Exchange {
+            access: AccessEntry::new(&request, "127.0.0.1"),
+            capture: None,
+        };
+        let deadlines = Deadlines {
+            sent_at: Instant::now(),
+            first_byte: Duration::from_millis(20),
+            response: None,
+        };
+        // the tunnel holds on to the sender and never answers
+        let (_tx_http, rx_http) = mpsc::channel(1);
+        let (mut browser, mut server) = tokio::io::duplex(4096);
+        let outcome = wait_for_tcp_response(
+            rx_http,
+            &mut server,
+            &request,
+            None,
+            &Metrics::default(),
+            &deadlines,
+            &mut exchange,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome, ResponseOutcome::TimedOut));
+        assert_eq!(exchange.access.status, 504);
+
+        drop(server);
+        let mut response = String::new();
+        browser.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_upload_is_not_timed_out() {
+        use crate::capture::Captures;
+        use crate::custom_domain::CustomDomains;
+        use crate::shared::TunnelInfo;
+
+        let shared_state = SharedState::new(
+            Duration::from_secs(30),
+            CustomDomains::new(&[], false, ([127, 0, 0, 1], 53).into()),
+            Captures::new(0, 0),
+        );
+        let (tx_ticket, mut rx_ticket) = mpsc::unbounded_channel();
+        let info = TunnelInfo::new("myapp", "acme", "127.0.0.1:5000".parse().unwrap());
+        shared_state
+            .register_tcp_client(tx_ticket, Arc::new(info))
+            .await;
+        let timeouts = Timeouts {
+            first_byte_secs: 1,
+            ..Timeouts::default()
+        };
+        let (mut browser, server) = tokio::io::duplex(4096);
+        let tunnel_state = shared_state.clone();
+        tokio::spawn(async move {
+            HttpServer::handle_connection(
+                server,
+                "127.0.0.1:40000".parse().unwrap(),
+                tunnel_state,
+                Router::new(&["example.com".to_string()], false),
+                Limits::default(),
+                timeouts,
+                AccessLogFormat::Off,
+            )
+            .await
+            .is_ok()
+        });
+
+        // the tunnel answers a moment after the whole body is in
+        let tunnel_state = shared_state.clone();
+        let tunnel = tokio::spawn(async move {
+            let mut ticket = rx_ticket.recv().await.unwrap();
+            let mut body = ticket.data.split_off(ticket.data.len() - 1);
+            let mut rx_body = ticket.body.take().unwrap();
+            while let Some(chunk) = rx_body.recv().await {
+                body.extend_from_slice(&chunk);
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let tx_http = tunnel_state.http_sender(&ticket.name).unwrap();
+            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+            tx_http.send(ResponseEvent::Data(response)).await.unwrap();
+            tx_http.send(ResponseEvent::End).await.unwrap();
+            body
+        });
+
+        // steady but slow, the upload alone takes longer than `first_byte_secs`
+        browser
+            .write_all(b"POST / HTTP/1.1\r\nHost: myapp.example.com\r\nContent-Length: 6\r\n\r\na")
+            .await
+            .unwrap();
+        for byte in [b"b", b"c", b"d", b"e", b"f"] {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            browser.write_all(byte).await.unwrap();
+        }
+        assert_eq!(tunnel.await.unwrap(), b"abcdef");
+        let mut response = vec![0u8; 1024];
+        let n = browser.read(&mut response).await.unwrap();
+        assert!(
+            response[..n].starts_with(b"HTTP/1.1 200 OK\r\n"),
+            "{}",
+            String::from_utf8_lossy(&response[..n])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipe_upgraded_after_half_close() {
+        let (tx_http, rx_http) = mpsc::channel(4);
+        let (tx_body, mut rx_body) = mpsc::channel(4);
+        let (mut public, mut server) = tokio::io::duplex(4096);
+        let pipe = tokio::spawn(async move {
+            pipe_upgraded(&mut server, rx_http, tx_body, Vec::new(), 1024)
+                .await
+                .is_ok()
+        });
+
+        public.write_all(b"x\n").await.unwrap();
+        public.shutdown().await.unwrap();
+        assert_eq!(rx_body.recv().await, Some(b"x\n".to_vec()));
+        // the tunnel learns the public side is done and answers afterwards
+        assert_eq!(rx_body.recv().await, None);
+        tx_http
+            .send(ResponseEvent::Data(b"reply\n".to_vec()))
+            .await
+            .unwrap();
+        tx_http.send(ResponseEvent::End).await.unwrap();
+
+        let mut reply = Vec::new();
+        public.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply\n");
+        assert!(pipe.await.unwrap());
+    }
 }
@@ -1,137 +1,273 @@
+mod access_log;
+mod admin;
+mod auth;
+mod body;
+mod capture;
+mod codec;
+mod config;
+mod custom_domain;
+mod dns;
+mod frame;
+mod http;
 mod http_server;
-mod request;
+mod metrics;
+mod path_prefix;
+mod port_pool;
+mod registry;
+mod replay;
+mod reservation;
 mod response;
+mod routing;
 mod shared;
+mod sni;
 mod tcp_server;
+mod tcp_tunnel;
+mod tls;
+mod udp_tunnel;
+mod watch;
 
+use access_log::{ACCESS_LOG_TARGET, AccessLogFormat};
+use admin::AdminServer;
+use capture::Captures;
+use clap::Parser;
+use config::{Cli, LogRotation, ServerConfig};
 use http_server::HttpServer;
 use shared::SharedState;
-use std::env;
-use tcp_server::TcpServer;
+use sni::SniServer;
+use std::time::Duration;
+use tcp_server::{TcpServer, TunnelSettings};
 use tracing::info;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{LevelFilter, Targets};
 use tracing_subscriber::fmt;
 use tracing_subscriber::prelude::*;
 
-#[derive(Debug, Clone)]
-pub struct ServerConfig {
-    pub http_port: u16,
-    pub tcp_port: u16,
-    pub http_addr: String,
-    pub tcp_addr: String,
-}
-
-const TXT_INVALID_PORT: &str = "Invalid port";
-
-impl ServerConfig {
-    pub fn from_args() -> Result<Self, Box<dyn std::error::Error>> {
-        let args: Vec<String> = env::args().collect();
-
-        let http_port = if args.len() > 1 {
-            args[1]
-                .parse::<u16>()
-                .map_err(|_| format!("{TXT_INVALID_PORT}: {}", args[1]))?
-        } else {
-            8080
-        };
-
-        let tcp_port = if args.len() > 2 {
-            args[2]
-                .parse::<u16>()
-                .map_err(|_| format!("{TXT_INVALID_PORT}: {}", args[2]))?
-        } else {
-            9090
-        };
-
-        Ok(ServerConfig {
-            http_addr: format!("0.0.0.0:{http_port}"),
-            tcp_addr: format!("0.0.0.0:{tcp_port}"),
-            http_port,
-            tcp_port,
-        })
+fn log_file(
+    config: &ServerConfig,
+    prefix: &str,
+) -> Result<RollingFileAppender, Box<dyn std::error::Error>> {
+    let rotation = match config.log_rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Weekly => Rotation::WEEKLY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(prefix);
+    if let Some(max_files) = config.log_max_files {
+        builder = builder.max_log_files(max_files);
     }
+    Ok(builder.build(&config.log_dir)?)
 }
 
-fn setup_logging() -> tracing_appender::non_blocking::WorkerGuard {
-    let file_appender = tracing_appender::rolling::daily("logs", "bindlocal-server.log");
-    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+// the guards flush the files when dropped, keep them until the end of main
+fn setup_logging(config: &ServerConfig) -> Result<Vec<WorkerGuard>, Box<dyn std::error::Error>> {
+    let level: LevelFilter = config.log_level.parse()?;
+    let (server_log, server_guard) =
+        tracing_appender::non_blocking(log_file(config, "bindlocal-server.log")?);
+    let mut guards = vec![server_guard];
+
+    // access lines are already formatted, they get a file of their own without a prefix
+    let access_layer = if config.access_log_format == AccessLogFormat::Off {
+        None
+    } else {
+        let (access_log, access_guard) =
+            tracing_appender::non_blocking(log_file(config, "access.log")?);
+        guards.push(access_guard);
+        Some(
+            fmt::layer()
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .with_target(false)
+                .with_writer(access_log)
+                .with_filter(Targets::new().with_target(ACCESS_LOG_TARGET, LevelFilter::TRACE)),
+        )
+    };
 
     tracing_subscriber::registry()
-        .with(fmt::layer().with_target(false))
-        .with(fmt::layer().with_target(false).with_ansi(false).with_writer(non_blocking))
-        .with(tracing_subscriber::filter::LevelFilter::INFO)
+        .with(fmt::layer().with_target(false).with_filter(level))
+        .with(
+            fmt::layer()
+                .with_target(false)
+                .with_ansi(false)
+                .with_writer(server_log)
+                .with_filter(
+                    Targets::new()
+                        .with_default(level)
+                        .with_target(ACCESS_LOG_TARGET, LevelFilter::OFF),
+                ),
+        )
+        .with(access_layer)
         .init();
 
-    guard
+    Ok(guards)
 }
 
 fn print_startup_info(config: &ServerConfig) {
     info!("Starting servers..");
-    info!("HTTP Server will run on http://{}", config.http_addr);
-    info!("TCP Server will run on tcp://{}", config.tcp_addr);
+    let scheme = if config.tls.cert.is_empty() {
+        "http"
+    } else {
+        "https"
+    };
+    info!("HTTP Server will run on {scheme}://{}", config.http_addr);
+    let scheme = if config.tunnel_tls.cert.is_empty() {
+        "tcp"
+    } else {
+        "tls"
+    };
+    info!("TCP Server will run on {scheme}://{}", config.tcp_addr);
+    if let Some(sni_addr) = config.sni_addr {
+        info!("TLS passthrough will run on tcp://{sni_addr}");
+    }
+    if let Some(admin_addr) = config.admin.addr {
+        info!("Admin API will run on http://{admin_addr}");
+    }
+    for base_domain in &config.base_domains {
+        info!("Tunnels are served as <name>.{base_domain}");
+    }
+}
+
+// the optional listeners are `None` unless configured
+struct Servers {
+    http: HttpServer,
+    tcp: TcpServer,
+    sni: Option<SniServer>,
+    admin: Option<AdminServer>,
 }
 
 async fn initialize_servers(
     config: &ServerConfig,
     shared_state: SharedState,
-) -> Result<(HttpServer, TcpServer), Box<dyn std::error::Error>> {
-    let http_server = HttpServer::new(&config.http_addr, shared_state.clone()).await?;
-    let tcp_server = TcpServer::new(&config.tcp_addr, shared_state.clone()).await?;
+) -> Result<Servers, Box<dyn std::error::Error>> {
+    let authenticator = auth::build_authenticator(
+        config.auth.tokens_file.as_deref(),
+        config.auth.hmac_secret.as_deref(),
+    )?;
+    let tls_config = if config.tls.cert.is_empty() {
+        None
+    } else {
+        let resolver = tls::SniResolver::load_and_watch(config.tls.cert_paths())?;
+        Some(tls::server_config(resolver)?)
+    };
+    let router = routing::Router::new(&config.base_domains, config.path_routing);
+    let http_server = HttpServer::new(
+        config.http_addr,
+        shared_state.clone(),
+        tls_config,
+        router.clone(),
+        config.limits.clone(),
+        config.timeouts.clone(),
+        config.access_log_format,
+    )
+    .await?;
+    let sni_server = match config.sni_addr {
+        Some(addr) => {
+            Some(SniServer::new(addr, shared_state.clone(), router, config.limits.clone()).await?)
+        }
+        None => None,
+    };
+    let tunnel_tls_config = if config.tunnel_tls.cert.is_empty() {
+        None
+    } else {
+        let resolver = tls::SniResolver::load_and_watch(config.tunnel_tls.cert_paths())?;
+        let client_ca = config
+            .tunnel_tls
+            .client_ca
+            .as_deref()
+            .map(std::path::Path::new);
+        Some(tls::tunnel_server_config(resolver, client_ca)?)
+    };
+    let tcp_server = TcpServer::new(
+        config.tcp_addr,
+        shared_state.clone(),
+        authenticator,
+        tunnel_tls_config,
+        TunnelSettings {
+            minimum_client_version: config.minimum_client_version.clone(),
+            limits: config.limits.clone(),
+            tcp_ports: config
+                .tcp_tunnel_ports
+                .map(|range| port_pool::PortPool::new(config.http_addr.ip(), range)),
+            udp_ports: config
+                .udp_tunnel_ports
+                .map(|range| port_pool::PortPool::new(config.http_addr.ip(), range)),
+            udp_session_idle: Duration::from_secs(config.udp_session_idle_secs),
+            udp_max_sessions: config.udp_max_sessions,
+            handshake_timeout: Duration::from_secs(config.timeouts.handshake_secs),
+        },
+    )
+    .await?;
+
+    let admin_server = match (config.admin.addr, &config.admin.token) {
+        (Some(addr), Some(token)) => Some(
+            AdminServer::new(
+                addr,
+                shared_state.clone(),
+                token.clone(),
+                config.timeouts.clone(),
+            )
+            .await?,
+        ),
+        _ => None,
+    };
 
-    Ok((http_server, tcp_server))
+    Ok(Servers {
+        http: http_server,
+        tcp: tcp_server,
+        sni: sni_server,
+        admin: admin_server,
+    })
 }
 
-async fn run_servers(
-    http_server: HttpServer,
-    tcp_server: TcpServer,
-) -> Result<(), Box<dyn std::error::Error>> {
-    tokio::try_join!(http_server.run(), tcp_server.run())?;
+async fn run_servers(servers: Servers) -> Result<(), Box<dyn std::error::Error>> {
+    let sni = async {
+        match servers.sni {
+            Some(server) => server.run().await,
+            None => Ok(()),
+        }
+    };
+    let admin = async {
+        match servers.admin {
+            Some(server) => server.run().await,
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(servers.http.run(), servers.tcp.run(), sni, admin)?;
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = ServerConfig::from_args()?;
-    let _log_guard = setup_logging();
+    let cli = Cli::parse();
+    let config = ServerConfig::load(&cli, |name| std::env::var(name).ok())?;
+    if cli.check {
+        println!("configuration is valid");
+        return Ok(());
+    }
+    let _log_guards = setup_logging(&config)?;
     print_startup_info(&config);
 
-    let shared_state = SharedState::new();
-    let (http_server, tcp_server) = initialize_servers(&config, shared_state).await?;
-    run_servers(http_server, tcp_server).await?;
-
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_server_config_default_ports() {
-        let config = ServerConfig {
-            http_port: 8080,
-            tcp_port: 9090,
-            http_addr: "0.0.0.0:8080".to_string(),
-            tcp_addr: "0.0.0.0:9090".to_string(),
-        };
-
-        assert_eq!(config.http_port, 8080);
-        assert_eq!(config.tcp_port, 9090);
-        assert_eq!(config.http_addr, "0.0.0.0:8080");
-        assert_eq!(config.tcp_addr, "0.0.0.0:9090");
+    let custom_domains = custom_domain::CustomDomains::new(
+        &config.base_domains,
+        config.verify_custom_domains,
+        config.custom_domain_resolver,
+    );
+    let shared_state = SharedState::new(
+        Duration::from_secs(config.reconnect_grace_secs),
+        custom_domains,
+        Captures::new(config.capture.requests, config.capture.max_body_size),
+    );
+    if let Some(path) = &config.reservations_file {
+        shared_state.reservations.load_and_watch(path)?;
     }
+    shared_state.spawn_reaper();
+    let servers = initialize_servers(&config, shared_state).await?;
+    run_servers(servers).await?;
 
-    #[test]
-    fn test_server_config_custom_ports() {
-        let config = ServerConfig {
-            http_port: 3000,
-            tcp_port: 4000,
-            http_addr: "0.0.0.0:3000".to_string(),
-            tcp_addr: "0.0.0.0:4000".to_string(),
-        };
-
-        assert_eq!(config.http_port, 3000);
-        assert_eq!(config.tcp_port, 4000);
-        assert_eq!(config.http_addr, "0.0.0.0:3000");
-        assert_eq!(config.tcp_addr, "0.0.0.0:4000");
-    }
+    Ok(())
 }
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::codec::ErrorCode;
+use crate::shared::TunnelInfo;
+
+// upper bounds in seconds for the time to the first response byte
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+// name, type, help and how to read the value off a tunnel
+type TunnelSeries = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&TunnelInfo) -> u64,
+);
+
+/// Counters and histograms served on `/metrics` in the Prometheus text format.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    http_responses: [AtomicU64; STATUS_CLASSES.len()],
+    first_byte: Histogram,
+    handshake_failures: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+struct Histogram {
+    // per bucket, made cumulative when rendered
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Counts a response to a browser, whether the tunnel or the server itself answered.
+    pub fn record_status(&self, status: u16) {
+        let class = (status / 100).clamp(1, 5) as usize - 1;
+        self.inner.http_responses[class].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time from handing a request to the tunnel until its first response bytes came back.
+    pub fn record_first_byte(&self, elapsed: Duration) {
+        self.inner.first_byte.observe(elapsed);
+    }
+
+    pub fn record_handshake_failure(&self, code: ErrorCode) {
+        let mut failures = self.inner.handshake_failures.lock().unwrap();
+        *failures.entry(code.as_str()).or_default() += 1;
+    }
+
+    pub fn render(&self, tunnels: &[Arc<TunnelInfo>]) -> String {
+        let mut out = String::new();
+        let inner = &self.inner;
+
+        header(
+            &mut out,
+            "bindlocal_tunnels_active",
+            "gauge",
+            "Connected tunnels.",
+        );
+        let _ = writeln!(out, "bindlocal_tunnels_active {}", tunnels.len());
+
+        header(
+            &mut out,
+            "bindlocal_http_responses_total",
+            "counter",
+            "Responses to browsers by status class.",
+        );
+        for (class, count) in STATUS_CLASSES.iter().zip(&inner.http_responses) {
+            let _ = writeln!(
+                out,
+                "bindlocal_http_responses_total{{class=\"{class}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "bindlocal_first_byte_seconds",
+            "histogram",
+            "Time from sending a request into a tunnel to its first response byte.",
+        );
+        let histogram = &inner.first_byte;
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "bindlocal_first_byte_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(
+            out,
+            "bindlocal_first_byte_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(out, "bindlocal_first_byte_seconds_sum {sum}");
+        let _ = writeln!(out, "bindlocal_first_byte_seconds_count {count}");
+
+        header(
+            &mut out,
+            "bindlocal_handshake_failures_total",
+            "counter",
+            "Rejected tunnel handshakes by error code.",
+        );
+        for (code, count) in inner.handshake_failures.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "bindlocal_handshake_failures_total{{code=\"{code}\"}} {count}"
+            );
+        }
+
+        let per_tunnel: [TunnelSeries; 5] = [
+            (
+                "bindlocal_tunnel_received_bytes_total",
+                "counter",
+                "Bytes read from the tunnel client.",
+                |info| info.bytes_received.load(Ordering::Relaxed),
+            ),
+            (
+                "bindlocal_tunnel_sent_bytes_total",
+                "counter",
+                "Bytes written to the tunnel client.",
+                |info| info.bytes_sent.load(Ordering::Relaxed),
+            ),
+            (
+                "bindlocal_tunnel_in_flight_requests",
+                "gauge",
+                "Streams open on the tunnel.",
+                |info| info.in_flight.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "bindlocal_tunnel_ticket_queue_depth",
+                "gauge",
+                "Requests waiting for the tunnel to open a stream.",
+                |info| info.ticket_queue.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "bindlocal_tunnel_frame_queue_depth",
+                "gauge",
+                "Frames waiting to be written to the tunnel client.",
+                |info| info.frame_queue.load(Ordering::Relaxed) as u64,
+            ),
+        ];
+        for (name, kind, help, value) in per_tunnel {
+            header(&mut out, name, kind, help);
+            for info in tunnels {
+                let tunnel = escape_label(&info.name());
+                let _ = writeln!(out, "{name}{{tunnel=\"{tunnel}\"}} {}", value(info));
+            }
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_status(200);
+        metrics.record_status(204);
+        metrics.record_status(503);
+        metrics.record_first_byte(Duration::from_millis(30));
+        metrics.record_first_byte(Duration::from_secs(60));
+        metrics.record_handshake_failure(ErrorCode::Unauthorized);
+
+        let info = Arc::new(TunnelInfo::new(
+            "myapp",
+            "acme",
+            "127.0.0.1:5000".parse().unwrap(),
+        ));
+        info.bytes_received.store(42, Ordering::Relaxed);
+        let text = metrics.render(&[info]);
+
+        for line in [
+            "bindlocal_tunnels_active 1",
+            "bindlocal_http_responses_total{class=\"2xx\"} 2",
+            "bindlocal_http_responses_total{class=\"5xx\"} 1",
+            "bindlocal_first_byte_seconds_bucket{le=\"0.025\"} 0",
+            "bindlocal_first_byte_seconds_bucket{le=\"0.05\"} 1",
+            "bindlocal_first_byte_seconds_bucket{le=\"30\"} 1",
+            "bindlocal_first_byte_seconds_bucket{le=\"+Inf\"} 2",
+            "bindlocal_first_byte_seconds_count 2",
+            "bindlocal_handshake_failures_total{code=\"unauthorized\"} 1",
+            "bindlocal_tunnel_received_bytes_total{tunnel=\"myapp\"} 42",
+            "# TYPE bindlocal_tunnel_frame_queue_depth gauge",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -0,0 +1,175 @@
+const CRLF: &[u8] = b"\r\n";
+const LOCATION: &str = "location";
+const SET_COOKIE: &str = "set-cookie";
+
+/// The request head with `prefix` taken off its target, the app answers as if it lived at `/`.
+pub fn strip_request_prefix(head: &[u8], prefix: &str) -> Vec<u8> {
+    let line_end = head
+        .windows(CRLF.len())
+        .position(|w| w == CRLF)
+        .unwrap_or(head.len());
+    let Ok(request_line) = std::str::from_utf8(&head[..line_end]) else {
+        return head.to_vec();
+    };
+    let mut parts = request_line.splitn(3, ' ');
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return head.to_vec();
+    };
+    let Some(target) = target.strip_prefix(prefix) else {
+        return head.to_vec();
+    };
+
+    let mut rewritten = format!("{method} {target} {version}").into_bytes();
+    rewritten.extend_from_slice(&head[line_end..]);
+    rewritten
+}
+
+/// The response head with `prefix` put back on `Location` and on cookie paths.
+/// `host` is the `Host` the browser asked for, absolute redirects to it get the prefix too.
+pub fn prefix_response_head(head: &[u8], prefix: &str, host: Option<&str>) -> Vec<u8> {
+    let lines: Vec<&[u8]> = split_lines(head);
+    let mut rewritten = Vec::with_capacity(head.len() + prefix.len());
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            rewritten.extend_from_slice(CRLF);
+        }
+        match rewrite_field(line, prefix, host) {
+            Some(field) => rewritten.extend_from_slice(field.as_bytes()),
+            None => rewritten.extend_from_slice(line),
+        }
+    }
+    rewritten
+}
+
+fn split_lines(head: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut rest = head;
+    while let Some(pos) = rest.windows(CRLF.len()).position(|w| w == CRLF) {
+        lines.push(&rest[..pos]);
+        rest = &rest[pos + CRLF.len()..];
+    }
+    lines.push(rest);
+    lines
+}
+
+fn rewrite_field(line: &[u8], prefix: &str, host: Option<&str>) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (name, value) = line.split_once(':')?;
+    let value = value.trim();
+    let value = if name.eq_ignore_ascii_case(LOCATION) {
+        prefix_location(value, prefix, host)?
+    } else if name.eq_ignore_ascii_case(SET_COOKIE) {
+        prefix_cookie_path(value, prefix)?
+    } else {
+        return None;
+    };
+    Some(format!("{name}: {value}"))
+}
+
+fn prefix_location(location: &str, prefix: &str, host: Option<&str>) -> Option<String> {
+    if location.starts_with('/') && !location.starts_with("//") {
+        return Some(format!("{prefix}{location}"));
+    }
+    // relative references already resolve under the prefix
+    let host = host?;
+    for scheme in ["http://", "https://"] {
+        let Some(path) = location
+            .strip_prefix(scheme)
+            .and_then(|rest| rest.strip_prefix(host))
+        else {
+            continue;
+        };
+        let separator = if path.starts_with('/') { "" } else { "/" };
+        if path.is_empty() || path.starts_with(['/', '?', '#']) {
+            return Some(format!("{scheme}{host}{prefix}{separator}{path}"));
+        }
+    }
+    None
+}
+
+fn prefix_cookie_path(cookie: &str, prefix: &str) -> Option<String> {
+    let mut changed = false;
+    let attributes: Vec<String> = cookie
+        .split(';')
+        .map(|attribute| {
+            let trimmed = attribute.trim_start();
+            let leading = &attribute[..attribute.len() - trimmed.len()];
+            match trimmed.split_once('=') {
+                Some((name, path))
+                    if name.eq_ignore_ascii_case("path") && path.starts_with('/') =>
+                {
+                    changed = true;
+                    format!("{leading}{name}={prefix}{path}")
+                }
+                _ => attribute.to_string(),
+            }
+        })
+        .collect();
+    changed.then(|| attributes.join(";"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_request_prefix() {
+        let head = b"GET /t/myapp/api/x?y=1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            strip_request_prefix(head, "/t/myapp"),
+            b"GET /api/x?y=1 HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        );
+        let head = b"GET /other HTTP/1.1\r\n\r\n";
+        assert_eq!(strip_request_prefix(head, "/t/myapp"), head);
+    }
+
+    #[test]
+    fn test_prefix_location() {
+        let host = Some("localhost:8080");
+        assert_eq!(
+            prefix_location("/login", "/t/app", host).as_deref(),
+            Some("/t/app/login")
+        );
+        assert_eq!(
+            prefix_location("http://localhost:8080/login?next=1", "/t/app", host).as_deref(),
+            Some("http://localhost:8080/t/app/login?next=1")
+        );
+        assert_eq!(
+            prefix_location("https://localhost:8080", "/t/app", host).as_deref(),
+            Some("https://localhost:8080/t/app/")
+        );
+        assert_eq!(
+            prefix_location("https://example.com/", "/t/app", host),
+            None
+        );
+        assert_eq!(
+            prefix_location("http://localhost:8080.evil/", "/t/app", host),
+            None
+        );
+        assert_eq!(prefix_location("//cdn.example.com/x", "/t/app", host), None);
+        assert_eq!(prefix_location("next", "/t/app", host), None);
+    }
+
+    #[test]
+    fn test_prefix_cookie_path() {
+        assert_eq!(
+            prefix_cookie_path("sid=1; Path=/; HttpOnly", "/t/app").as_deref(),
+            Some("sid=1; Path=/t/app/; HttpOnly")
+        );
+        assert_eq!(
+            prefix_cookie_path("sid=1;path=/api", "/t/app").as_deref(),
+            Some("sid=1;path=/t/app/api")
+        );
+        assert_eq!(prefix_cookie_path("sid=1; HttpOnly", "/t/app"), None);
+    }
+
+    #[test]
+    fn test_prefix_response_head() {
+        let head = b"HTTP/1.1 302 Found\r\nlocation: /login\r\nSet-Cookie: a=1; Path=/\r\nSet-Cookie: b=2\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(
+            prefix_response_head(head, "/t/app", None),
+            b"HTTP/1.1 302 Found\r\nlocation: /t/app/login\r\nSet-Cookie: a=1; Path=/t/app/\r\nSet-Cookie: b=2\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::PortRange;
+
+/// Public ports for raw TCP or UDP tunnels, each one held by a single tunnel at a time.
+#[derive(Clone)]
+pub struct PortPool {
+    ip: IpAddr,
+    range: PortRange,
+    in_use: Arc<Mutex<HashSet<u16>>>,
+}
+
+/// A port taken from the pool, it goes back when the lease is dropped.
+pub struct PortLease {
+    port: u16,
+    in_use: Arc<Mutex<HashSet<u16>>>,
+}
+
+impl PortLease {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.in_use.lock().unwrap().remove(&self.port);
+    }
+}
+
+impl PortPool {
+    pub fn new(ip: IpAddr, range: PortRange) -> Self {
+        Self {
+            ip,
+            range,
+            in_use: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub async fn bind_tcp(&self, requested: u16) -> Result<(TcpListener, PortLease), String> {
+        self.bind_with(requested, TcpListener::bind).await
+    }
+
+    pub async fn bind_udp(&self, requested: u16) -> Result<(UdpSocket, PortLease), String> {
+        self.bind_with(requested, UdpSocket::bind).await
+    }
+
+    /// Binds `requested`, or with `0` the first free port of the range.
+    async fn bind_with<T, F, Fut>(&self, requested: u16, bind: F) -> Result<(T, PortLease), String>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let candidates: Vec<u16> = if requested == 0 {
+            (self.range.start..=self.range.end).collect()
+        } else if self.range.contains(requested) {
+            vec![requested]
+        } else {
+            return Err(format!("port {requested} is outside {}", self.range));
+        };
+
+        for port in candidates {
+            let Some(lease) = self.lease(port) else {
+                continue;
+            };
+            // something outside the server may hold the port as well
+            if let Ok(bound) = bind(SocketAddr::new(self.ip, port)).await {
+                return Ok((bound, lease));
+            }
+        }
+        Err(match requested {
+            0 => format!("no free port in {}", self.range),
+            port => format!("port {port} is in use"),
+        })
+    }
+
+    fn lease(&self, port: u16) -> Option<PortLease> {
+        let mut in_use = self.in_use.lock().unwrap();
+        in_use.insert(port).then(|| PortLease {
+            port,
+            in_use: self.in_use.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn pool(start: u16, end: u16) -> PortPool {
+        PortPool::new(IpAddr::V4(Ipv4Addr::LOCALHOST), PortRange { start, end })
+    }
+
+    #[tokio::test]
+    async fn test_bind_hands_out_free_ports() {
+        // a free port picked by the OS
+        let probe = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+        let pool = pool(port, port);
+
+        let (listener, lease) = pool.bind_tcp(0).await.unwrap();
+        assert_eq!(lease.port(), port);
+        assert_eq!(
+            pool.bind_tcp(port).await.err().unwrap(),
+            format!("port {port} is in use")
+        );
+        assert!(pool.bind_tcp(0).await.is_err());
+
+        // dropping the lease frees the port again
+        drop(listener);
+        drop(lease);
+        let (_listener, lease) = pool.bind_tcp(port).await.unwrap();
+        assert_eq!(lease.port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_bind_outside_range() {
+        let error = pool(20000, 20010).bind_tcp(30000).await.err().unwrap();
+        assert_eq!(error, "port 30000 is outside 20000-20010");
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp() {
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+        let pool = pool(port, port);
+        let (_socket, lease) = pool.bind_udp(0).await.unwrap();
+        assert_eq!(lease.port(), port);
+        assert!(pool.bind_udp(0).await.is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+enum EntryState {
+    Active,
+    // the tunnel dropped, only the matching resume token can take the name until `until`
+    Released { until: Instant },
+}
+
+struct Entry {
+    resume_token: String,
+    state: EntryState,
+}
+
+/// The single owner of subdomain names, shared by every connection task.
+#[derive(Clone)]
+pub struct SubdomainRegistry {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    grace: Duration,
+}
+
+impl SubdomainRegistry {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            grace,
+        }
+    }
+
+    /// Takes `name` if nobody holds it and returns the resume token for it.
+    pub fn claim(&self, name: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|_, entry| match entry.state {
+            EntryState::Active => true,
+            EntryState::Released { until } => until > now,
+        });
+        if entries.contains_key(name) {
+            return None;
+        }
+
+        let resume_token = generate_resume_token();
+        entries.insert(
+            name.to_string(),
+            Entry {
+                resume_token: resume_token.clone(),
+                state: EntryState::Active,
+            },
+        );
+        Some(resume_token)
+    }
+
+    /// Gives a released name back to the client holding its resume token, within the grace period.
+    pub fn resume(&self, resume_token: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let (name, entry) = entries.iter_mut().find(|(_, entry)| {
+            matches!(entry.state, EntryState::Released { until } if until > now)
+                && entry.resume_token == resume_token
+        })?;
+        entry.state = EntryState::Active;
+        Some(name.clone())
+    }
+
+    /// Moves an active name with its resume token to `new_name`, the old name is free right away.
+    pub fn rename(&self, name: &str, new_name: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|_, entry| match entry.state {
+            EntryState::Active => true,
+            EntryState::Released { until } => until > now,
+        });
+        let active = entries
+            .get(name)
+            .is_some_and(|entry| matches!(entry.state, EntryState::Active));
+        if !active || entries.contains_key(new_name) {
+            return false;
+        }
+        let Some(entry) = entries.remove(name) else {
+            return false;
+        };
+        entries.insert(new_name.to_string(), entry);
+        true
+    }
+
+    /// Called when a tunnel drops, the name stays held for the grace period.
+    pub fn release(&self, name: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if self.grace.is_zero() {
+            entries.remove(name);
+        } else if let Some(entry) = entries.get_mut(name) {
+            entry.state = EntryState::Released {
+                until: Instant::now() + self.grace,
+            };
+        }
+    }
+}
+
+fn generate_resume_token() -> String {
+    let bytes: [u8; 16] = rand::rng().random();
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_once() {
+        let registry = SubdomainRegistry::new(Duration::from_secs(30));
+        let token = registry.claim("myapp");
+        assert!(token.is_some());
+        assert_eq!(token.unwrap().len(), 32);
+        assert!(registry.claim("myapp").is_none());
+    }
+
+    #[test]
+    fn test_claim_is_shared_between_clones() {
+        let registry = SubdomainRegistry::new(Duration::from_secs(30));
+        let clone = registry.clone();
+        assert!(registry.claim("myapp").is_some());
+        assert!(clone.claim("myapp").is_none());
+    }
+
+    #[test]
+    fn test_release_without_grace_frees_name() {
+        let registry = SubdomainRegistry::new(Duration::ZERO);
+        registry.claim("myapp").unwrap();
+        registry.release("myapp");
+        assert!(registry.claim("myapp").is_some());
+    }
+
+    #[test]
+    fn test_release_holds_name_for_grace_period() {
+        let registry = SubdomainRegistry::new(Duration::from_secs(30));
+        let token = registry.claim("myapp").unwrap();
+        registry.release("myapp");
+
+        assert!(registry.claim("myapp").is_none());
+        assert_eq!(registry.resume("wrong-token"), None);
+        assert_eq!(registry.resume(&token), Some("myapp".to_string()));
+        // active again, so the token cannot be used twice
+        assert_eq!(registry.resume(&token), None);
+    }
+
+    #[test]
+    fn test_resume_after_grace_period_fails() {
+        let registry = SubdomainRegistry::new(Duration::from_millis(1));
+        let token = registry.claim("myapp").unwrap();
+        registry.release("myapp");
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(registry.resume(&token), None);
+        assert!(registry.claim("myapp").is_some());
+    }
+
+    #[test]
+    fn test_rename_keeps_resume_token() {
+        let registry = SubdomainRegistry::new(Duration::from_secs(30));
+        let token = registry.claim("myapp").unwrap();
+        registry.claim("taken").unwrap();
+        assert!(!registry.rename("myapp", "taken"));
+        assert!(!registry.rename("missing", "other"));
+
+        assert!(registry.rename("myapp", "renamed"));
+        assert!(registry.claim("myapp").is_some());
+        registry.release("renamed");
+        assert_eq!(registry.resume(&token), Some("renamed".to_string()));
+    }
+
+    #[test]
+    fn test_resume_active_name_fails() {
+        let registry = SubdomainRegistry::new(Duration::from_secs(30));
+        let token = registry.claim("myapp").unwrap();
+        assert_eq!(registry.resume(&token), None);
+    }
+}
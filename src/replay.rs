@@ -0,0 +1,277 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::capture::Capture;
+use crate::http::parse_request;
+use crate::shared::{ResponseEvent, SharedState, StreamMode, TicketRequestHttp, stream_name};
+
+const CONTENT_LENGTH: &str = "Content-Length";
+const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+// the whole response has to be in by then
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+const RESPONSE_CHANNEL_CAPACITY: usize = 16;
+
+/// Changes to a captured request before it is sent again, all of them optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Overrides {
+    // replaces every header of that name, `null` removes it
+    pub headers: BTreeMap<String, Option<String>>,
+    // a new body, `Content-Length` follows it
+    pub body: Option<String>,
+    pub body_base64: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    // the capture does not hold the whole body and no new one was given
+    Truncated,
+    InvalidOverride(String),
+    TunnelGone,
+    // the tunnel gave up on the response partway
+    Aborted,
+    Timeout,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Truncated => write!(
+                f,
+                "the captured body was truncated, pass body or body_base64"
+            ),
+            ReplayError::InvalidOverride(reason) => write!(f, "{reason}"),
+            ReplayError::TunnelGone => write!(f, "the tunnel is not connected"),
+            ReplayError::Aborted => write!(f, "the tunnel aborted the response"),
+            ReplayError::Timeout => write!(f, "no complete response in time"),
+        }
+    }
+}
+
+/// The captured request as it goes out again, head and body.
+pub fn build_request(capture: &Capture, overrides: &Overrides) -> Result<Vec<u8>, ReplayError> {
+    let body = match (&overrides.body, &overrides.body_base64) {
+        (Some(_), Some(_)) => {
+            return Err(ReplayError::InvalidOverride(
+                "body and body_base64 cannot both be set".to_string(),
+            ));
+        }
+        (Some(text), None) => Some(text.as_bytes().to_vec()),
+        (None, Some(encoded)) => Some(
+            BASE64
+                .decode(encoded)
+                .map_err(|e| ReplayError::InvalidOverride(format!("body_base64: {e}")))?,
+        ),
+        (None, None) => None,
+    };
+    for (name, value) in &overrides.headers {
+        let invalid = |text: &str| text.contains(['\r', '\n']);
+        if name.is_empty()
+            || name.contains(':')
+            || invalid(name)
+            || value.as_deref().is_some_and(invalid)
+        {
+            return Err(ReplayError::InvalidOverride(format!(
+                "header {name:?} is not a valid header"
+            )));
+        }
+    }
+    if body.is_none() && capture.request_body.truncated {
+        return Err(ReplayError::Truncated);
+    }
+
+    let request = &capture.request;
+    let mut head = format!(
+        "{} {} {}\r\n",
+        request.method,
+        request.target,
+        request.version.as_str()
+    );
+    for (name, value) in request.headers.iter() {
+        let overridden = overrides
+            .headers
+            .keys()
+            .any(|key| key.eq_ignore_ascii_case(name));
+        // a new body brings its own length
+        let reframed = body.is_some()
+            && (name.eq_ignore_ascii_case(CONTENT_LENGTH)
+                || name.eq_ignore_ascii_case(TRANSFER_ENCODING));
+        if !overridden && !reframed {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    for (name, value) in &overrides.headers {
+        if let Some(value) = value {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    if let Some(body) = &body {
+        head.push_str(&format!("{CONTENT_LENGTH}: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+
+    let mut data = head.into_bytes();
+    data.extend_from_slice(body.as_deref().unwrap_or(&capture.request_body.bytes));
+    Ok(data)
+}
+
+/// Sends a captured request through its tunnel again, the answer is kept as a new capture
+/// next to the original.
+pub async fn replay(
+    shared_state: &SharedState,
+    capture: &Capture,
+    overrides: &Overrides,
+) -> Result<Arc<Capture>, ReplayError> {
+    let data = build_request(capture, overrides)?;
+    let (request, head_len) = parse_request(&data, data.len())
+        .map_err(|e| ReplayError::InvalidOverride(e.to_string()))?
+        .ok_or_else(|| ReplayError::InvalidOverride("incomplete request head".to_string()))?;
+    // a capture only exists while capturing is on
+    let mut recorder = shared_state
+        .captures
+        .recorder(&capture.tunnel, &capture.remote_addr, &request)
+        .ok_or(ReplayError::TunnelGone)?;
+    recorder.replay_of(capture.id);
+    recorder.request_body(&data[head_len..]);
+
+    let name = stream_name(&capture.tunnel, "tx");
+    let (tx_http, mut rx_http) = mpsc::channel(RESPONSE_CHANNEL_CAPACITY);
+    let pending = shared_state.register_http_client(name.clone(), tx_http);
+    let ticket = TicketRequestHttp {
+        name: name.clone(),
+        mode: StreamMode::Http,
+        head_request: request.is_head(),
+        data,
+        body: None,
+    };
+    let result = if shared_state
+        .send_to_tcp_client(&capture.tunnel, ticket)
+        .await
+    {
+        tokio::time::timeout(REPLAY_TIMEOUT, async {
+            loop {
+                match rx_http.recv().await {
+                    Some(ResponseEvent::Data(bytes)) => recorder.response(&bytes),
+                    Some(ResponseEvent::End) => return Ok(()),
+                    // a partial response is not kept as if it were the answer
+                    Some(ResponseEvent::Abort) | None => return Err(ReplayError::Aborted),
+                }
+            }
+        })
+        .await
+        .unwrap_or(Err(ReplayError::Timeout))
+    } else {
+        Err(ReplayError::TunnelGone)
+    };
+    drop(pending);
+    result?;
+    // the tunnel may have gone away meanwhile
+    recorder.finish().ok_or(ReplayError::TunnelGone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Captures;
+    use crate::http::DEFAULT_MAX_HEAD_SIZE;
+
+    fn capture(body: &[u8], max_body_size: usize) -> Arc<Capture> {
+        let raw = b"POST /hook HTTP/1.1\r\nHost: myapp.example.com\r\nX-Sig: a\r\nContent-Length: 6\r\n\r\n";
+        let (request, _) = parse_request(raw, DEFAULT_MAX_HEAD_SIZE).unwrap().unwrap();
+        let captures = Captures::new(5, max_body_size);
+        captures.open("myapp");
+        let mut recorder = captures.recorder("myapp", "203.0.113.7", &request).unwrap();
+        recorder.request_body(body);
+        recorder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_build_unchanged() {
+        let data = build_request(&capture(b"abcdef", 64), &Overrides::default()).unwrap();
+        assert_eq!(
+            data,
+            b"POST /hook HTTP/1.1\r\nHost: myapp.example.com\r\nX-Sig: a\r\nContent-Length: 6\r\n\r\nabcdef"
+        );
+    }
+
+    #[test]
+    fn test_build_with_overrides() {
+        let overrides = Overrides {
+            headers: BTreeMap::from([
+                ("x-sig".to_string(), Some("b".to_string())),
+                ("Host".to_string(), None),
+            ]),
+            body: Some("{}".to_string()),
+            body_base64: None,
+        };
+        let data = build_request(&capture(b"abcdef", 64), &overrides).unwrap();
+        assert_eq!(
+            data,
+            b"POST /hook HTTP/1.1\r\nx-sig: b\r\nContent-Length: 2\r\n\r\n{}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_aborted() {
+        use crate::custom_domain::CustomDomains;
+        use crate::shared::TunnelInfo;
+
+        let shared_state = SharedState::new(
+            Duration::from_secs(30),
+            CustomDomains::new(&[], false, ([127, 0, 0, 1], 53).into()),
+            Captures::new(5, 64),
+        );
+        let (tx_ticket, mut rx_ticket) = mpsc::unbounded_channel();
+        let info = TunnelInfo::new("myapp", "acme", "127.0.0.1:5000".parse().unwrap());
+        shared_state
+            .register_tcp_client(tx_ticket, Arc::new(info))
+            .await;
+        shared_state.captures.open("myapp");
+
+        let tunnel_state = shared_state.clone();
+        tokio::spawn(async move {
+            let ticket = rx_ticket.recv().await.unwrap();
+            let tx_http = tunnel_state.http_sender(&ticket.name).unwrap();
+            let head = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc".to_vec();
+            tx_http.send(ResponseEvent::Data(head)).await.unwrap();
+            tx_http.send(ResponseEvent::Abort).await.unwrap();
+        });
+
+        let original = capture(b"abcdef", 64);
+        let result = replay(&shared_state, &original, &Overrides::default()).await;
+        assert_eq!(result.map(|_| ()), Err(ReplayError::Aborted));
+        assert!(shared_state.captures.list("myapp").is_empty());
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let truncated = capture(b"abcdef", 4);
+        assert_eq!(
+            build_request(&truncated, &Overrides::default()),
+            Err(ReplayError::Truncated)
+        );
+        let overrides = Overrides {
+            body_base64: Some("/w==".to_string()),
+            ..Overrides::default()
+        };
+        assert_eq!(
+            build_request(&truncated, &overrides).unwrap().last(),
+            Some(&0xff)
+        );
+
+        let overrides = Overrides {
+            headers: BTreeMap::from([("X-Sig".to_string(), Some("a\r\nX-Evil: 1".to_string()))]),
+            ..Overrides::default()
+        };
+        assert!(matches!(
+            build_request(&truncated, &overrides),
+            Err(ReplayError::InvalidOverride(_))
+        ));
+    }
+}
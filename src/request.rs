@@ -1,134 +0,0 @@
-pub struct HttpRequest {}
-
-const CONTENT_LENGTH_HEADER: &str = "content-length:";
-const HOST_HEADER: &str = "host:";
-
-impl HttpRequest {
-    pub fn get_subdomain(request: &str) -> String {
-        for line in request.lines() {
-            if line.to_lowercase().starts_with("{HOST_HEADER}") {
-                let host = line.splitn(2, ':').nth(1).unwrap_or("").trim();
-                if let Some((subdomain, _rest)) = host.split_once('.') {
-                    return subdomain.to_string();
-                }
-            }
-        }
-        "".to_string()
-    }
-    pub fn parse_content_length(headers: String) -> Option<usize> {
-        for line in headers.lines() {
-            if line.to_lowercase().starts_with("{CONTENT_LENGTH_HEADER}") {
-                if let Some(value) = line.split(':').nth(1) {
-                    if let Ok(length) = value.trim().parse::<usize>() {
-                        return Some(length);
-                    }
-                }
-            }
-        }
-        None
-    }
-
-    pub fn parse_check_value_header(headers: String, key: &str) -> Option<String> {
-        for line in headers.lines() {
-            if line.to_lowercase().starts_with(key.to_lowercase().as_str()) {
-                if let Some(value) = line.split(':').nth(1) {
-                    return Some(value.trim().to_string());
-                }
-            }
-        }
-        None
-    }
-
-    pub fn parse_content_request_format(headers: String) -> String {
-        let line = headers.lines().nth(0);
-        if let Some(value) = line {
-            if let Some(space_index) = value.rfind(" ") {
-                return value[0..space_index].to_string() + " ";
-            }
-
-            return value.to_string();
-        }
-        "".to_string()
-    }
-}
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_empty_request() {
-        let result = HttpRequest::get_subdomain("");
-        assert_eq!(result, "");
-    }
-
-    #[test]
-    fn test_non_empty_request() {
-        let request = "GET / HTTP/1.1\r\nHost: test.example.com\r\n\r\n";
-        let result = HttpRequest::get_subdomain(request);
-        assert_eq!(result, "test");
-    }
-    #[test]
-    fn test_longname_url_request() {
-        let request = "GET / HTTP/1.1\r\nHost: app-test-10.test.example.com\r\n\r\n";
-        let result = HttpRequest::get_subdomain(request);
-        assert_eq!(result, "app-test-10");
-    }
-
-    #[test]
-    fn test_parse_content_length() {
-        let headers = "Content-Length: 123\r\n".to_string();
-        let result = HttpRequest::parse_content_length(headers);
-        assert_eq!(result, Some(123));
-    }
-
-    #[test]
-    fn test_parse_content_length_invalid() {
-        let headers = "Content-Length: abc\r\n".to_string();
-        let result = HttpRequest::parse_content_length(headers);
-        assert_eq!(result, None);
-    }
-
-    #[test]
-    fn test_parse_content_length_empty() {
-        let headers = "Content-Length:\r\n".to_string();
-        let result = HttpRequest::parse_content_length(headers);
-        assert_eq!(result, None);
-    }
-    #[test]
-    fn test_parse_connection() {
-        let headers = "Connection: keep-alive\r\n".to_string();
-        let result = HttpRequest::parse_check_value_header(headers, "connection");
-        assert_eq!(result, Some("keep-alive".to_string()));
-    }
-    #[test]
-    fn test_parse_connection_empty() {
-        let headers = "xxx:\r\n".to_string();
-        let result = HttpRequest::parse_check_value_header(headers, "connection");
-        assert_eq!(result, None);
-    }
-
-    #[test]
-    fn test_parse_content_request_format_normal() {
-        let headers = "GET / HTTP/1.1\r\nHost: test.example.com\r\n\r\n".to_string();
-        let result = HttpRequest::parse_content_request_format(headers);
-        assert_eq!(result, "GET / ");
-    }
-    #[test]
-    fn test_parse_content_request_format_favicon() {
-        let headers = "GET /favicon.ico HTTP/1.1\r\nHost: test.example.com\r\n\r\n".to_string();
-        let result = HttpRequest::parse_content_request_format(headers);
-        assert_eq!(result, "GET /favicon.ico ");
-    }
-    #[test]
-    fn test_parse_x_real_ip() {
-        let headers = "X-Real-IP: 192.168.1.1\r\n".to_string();
-        let result = HttpRequest::parse_check_value_header(headers, "X-Real-IP");
-        assert_eq!(result, Some("192.168.1.1".to_string()));
-    }
-    #[test]
-    fn test_parse_x_real_ip_empty() {
-        let headers = "X-Real-IP:\r\n".to_string();
-        let result = HttpRequest::parse_check_value_header(headers, "x-real-ip");
-        assert_eq!(result, Some("".to_string()));
-    }
-}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::watch::{WATCH_INTERVAL, watch_file};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationCheck {
+    // nobody reserved the subdomain
+    Free,
+    // the subdomain is reserved for the presented token
+    Owner,
+    // the subdomain is reserved for another token
+    Taken,
+}
+
+/// Subdomains that only a specific auth token may claim.
+#[derive(Clone, Default)]
+pub struct Reservations {
+    table: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl Reservations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads `<subdomain> <token>` lines from `path` and reloads them whenever the file changes.
+    pub fn load_and_watch(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.replace_all(Self::read(path)?);
+
+        let reservations = self.clone();
+        let path_owned = path.to_string();
+        watch_file(
+            PathBuf::from(path),
+            WATCH_INTERVAL,
+            move || match Self::read(&path_owned) {
+                Ok(table) => {
+                    tracing::info!("reloaded {} reserved subdomains", table.len());
+                    reservations.replace_all(table);
+                }
+                Err(e) => tracing::error!("keeping previous reservations: {e}"),
+            },
+        );
+        Ok(())
+    }
+
+    fn read(path: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read reservations file {path}: {e}"))?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> HashMap<String, String> {
+        let mut table = HashMap::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut parts = line.split_whitespace();
+            if let (Some(subdomain), Some(token)) = (parts.next(), parts.next()) {
+                table.insert(subdomain.to_lowercase(), token.to_string());
+            }
+        }
+        table
+    }
+
+    pub fn replace_all(&self, table: HashMap<String, String>) {
+        *self.table.write().unwrap() = table;
+    }
+
+    pub fn check(&self, subdomain: &str, token: Option<&str>) -> ReservationCheck {
+        match self.table.read().unwrap().get(&subdomain.to_lowercase()) {
+            None => ReservationCheck::Free,
+            Some(owner) if Some(owner.as_str()) == token => ReservationCheck::Owner,
+            Some(_) => ReservationCheck::Taken,
+        }
+    }
+
+    pub fn is_reserved(&self, subdomain: &str) -> bool {
+        self.table
+            .read()
+            .unwrap()
+            .contains_key(&subdomain.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reservations() {
+        let table = Reservations::parse("# webhooks\nStripe tok-a\n\nbroken\ngithub tok-b # ci\n");
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get("stripe"), Some(&"tok-a".to_string()));
+        assert_eq!(table.get("github"), Some(&"tok-b".to_string()));
+    }
+
+    #[test]
+    fn test_check_reservation() {
+        let reservations = Reservations::new();
+        reservations.replace_all(Reservations::parse("stripe tok-a"));
+
+        assert_eq!(
+            reservations.check("stripe", Some("tok-a")),
+            ReservationCheck::Owner
+        );
+        assert_eq!(
+            reservations.check("STRIPE", Some("tok-a")),
+            ReservationCheck::Owner
+        );
+        assert_eq!(
+            reservations.check("stripe", Some("tok-b")),
+            ReservationCheck::Taken
+        );
+        assert_eq!(reservations.check("stripe", None), ReservationCheck::Taken);
+        assert_eq!(
+            reservations.check("other", Some("tok-a")),
+            ReservationCheck::Free
+        );
+        assert!(reservations.is_reserved("stripe"));
+        assert!(!reservations.is_reserved("other"));
+    }
+
+    #[test]
+    fn test_replace_all_is_shared_between_clones() {
+        let reservations = Reservations::new();
+        let clone = reservations.clone();
+        clone.replace_all(Reservations::parse("stripe tok-a"));
+        assert!(reservations.is_reserved("stripe"));
+    }
+}
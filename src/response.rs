@@ -3,6 +3,7 @@ pub struct HttpResponse {
     status_text: String,
     content_type: String,
     body: String,
+    headers: Vec<(String, String)>,
 }
 
 impl HttpResponse {
@@ -12,9 +13,22 @@ impl HttpResponse {
             status_text: status_text.to_string(),
             content_type: content_type.to_string(),
             body: body.to_string(),
+            headers: Vec::new(),
         }
     }
 
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
+    pub fn moved_permanently(location: &str) -> Self {
+        let mut response = Self::new(301, "Moved Permanently", "text/plain", "moved");
+        response
+            .headers
+            .push(("Location".to_string(), location.to_string()));
+        response
+    }
+
     pub fn service_unavailable() -> Self {
         let body = "service unavailable";
         Self::new(503, "error", "text/plain", body)
@@ -39,6 +53,79 @@ impl HttpResponse {
         Self::new(404, "Not Found", "text/html", body)
     }
 
+    pub fn bad_request() -> Self {
+        let body = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>400 Bad Request</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; text-align: center; }
+        h1 { color: #d32f2f; }
+    </style>
+</head>
+<body>
+    <h1>400 - Bad Request</h1>
+    <p>The request could not be understood by the server.</p>
+</body>
+</html>"#;
+        Self::new(400, "Bad Request", "text/html", body)
+    }
+
+    pub fn header_fields_too_large() -> Self {
+        let body = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>431 Request Header Fields Too Large</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; text-align: center; }
+        h1 { color: #d32f2f; }
+    </style>
+</head>
+<body>
+    <h1>431 - Request Header Fields Too Large</h1>
+    <p>The request headers are larger than the server accepts.</p>
+</body>
+</html>"#;
+        Self::new(431, "Request Header Fields Too Large", "text/html", body)
+    }
+
+    pub fn misdirected_request() -> Self {
+        let body = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>421 Misdirected Request</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; text-align: center; }
+        h1 { color: #d32f2f; }
+    </style>
+</head>
+<body>
+    <h1>421 - Misdirected Request</h1>
+    <p>This server does not serve tunnels for the requested host.</p>
+</body>
+</html>"#;
+        Self::new(421, "Misdirected Request", "text/html", body)
+    }
+
+    pub fn gateway_timeout() -> Self {
+        let body = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>504 Gateway Timeout</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; text-align: center; }
+        h1 { color: #d32f2f; }
+    </style>
+</head>
+<body>
+    <h1>504 - Gateway Timeout</h1>
+    <p>The request or its response did not arrive in time.</p>
+    <a href="/">← Back to home</a>
+</body>
+</html>"#;
+        Self::new(504, "Gateway Timeout", "text/html", body)
+    }
+
     pub fn client_app_call_local_refused() -> Self {
         let body = r#"<!DOCTYPE html>
 <html>
@@ -57,21 +144,25 @@ impl HttpResponse {
 </html>"#;
         Self::new(503, "Service Unavailable", "text/html", body)
     }
+}
 
-    pub fn to_string(&self) -> String {
-        format!(
+impl std::fmt::Display for HttpResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
             "HTTP/1.1 {} {}\r\n\
              Content-Type: {}\r\n\
              Content-Length: {}\r\n\
              Connection: close\r\n\
-             Server: Tokio-HTTP/1.0\r\n\
-             \r\n\
-             {}",
+             Server: Tokio-HTTP/1.0\r\n",
             self.status_code,
             self.status_text,
             self.content_type,
             self.body.len(),
-            self.body
-        )
+        )?;
+        for (name, value) in &self.headers {
+            write!(f, "{name}: {value}\r\n")?;
+        }
+        write!(f, "\r\n{}", self.body)
     }
 }
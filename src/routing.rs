@@ -0,0 +1,220 @@
+use crate::http::RequestHead;
+
+// path routing serves tunnels under `/t/<name>/`
+const PATH_PREFIX: &str = "/t/";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    // the label right in front of the base domain names the tunnel, so
+    // `api.myapp.example.com` goes to `myapp` as well, with or without configured base domains
+    Tunnel(String),
+    // path routing, the tunnel sees the request without `prefix` and responses get it back
+    Prefixed { tunnel: String, prefix: String },
+    // `/t/<name>` without the slash, relative links only work under `/t/<name>/`
+    Redirect(String),
+    // a base domain itself, or a request without a tunnel label
+    NoTunnel,
+    // a host under none of the base domains
+    Misdirected,
+}
+
+/// Maps the `Host` of a request to the tunnel serving it.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    // normalized, longest first so `tunnel.example.com` wins over `example.com`
+    base_domains: Vec<String>,
+    // route by `/t/<name>/` instead of the host, for setups without wildcard DNS
+    path_routing: bool,
+}
+
+impl Router {
+    pub fn new(base_domains: &[String], path_routing: bool) -> Self {
+        let mut base_domains: Vec<String> = base_domains
+            .iter()
+            .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+            .collect();
+        base_domains.sort_by_key(|domain| std::cmp::Reverse(domain.len()));
+        Router {
+            base_domains,
+            path_routing,
+        }
+    }
+
+    pub fn route(&self, request: &RequestHead) -> Route {
+        if self.path_routing {
+            return route_path(&request.target);
+        }
+        match request.host() {
+            Some(host) => self.route_host(host),
+            None => Route::NoTunnel,
+        }
+    }
+
+    /// Maps a bare host name to its tunnel, also used for the SNI of passthrough connections.
+    pub fn route_host(&self, host: &str) -> Route {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if self.base_domains.is_empty() {
+            // the base domain is taken to be the last two labels, `example.com`,
+            // or the last one of a two label host such as `myapp.localhost`
+            let labels: Vec<&str> = host.split('.').collect();
+            if labels.len() < 2
+                || labels.iter().any(|label| label.is_empty())
+                || host.parse::<std::net::IpAddr>().is_ok()
+            {
+                return Route::NoTunnel;
+            }
+            let base_labels = if labels.len() > 2 { 2 } else { 1 };
+            return Route::Tunnel(labels[labels.len() - base_labels - 1].to_string());
+        }
+
+        for base_domain in &self.base_domains {
+            if host == *base_domain {
+                return Route::NoTunnel;
+            }
+            let Some(label) = host
+                .strip_suffix(base_domain.as_str())
+                .and_then(|rest| rest.strip_suffix('.'))
+            else {
+                continue;
+            };
+            if label.split('.').any(str::is_empty) {
+                return Route::NoTunnel;
+            }
+            let tunnel = label.rsplit('.').next().unwrap_or(label);
+            return Route::Tunnel(tunnel.to_string());
+        }
+        Route::Misdirected
+    }
+}
+
+fn route_path(target: &str) -> Route {
+    let Some(rest) = target.strip_prefix(PATH_PREFIX) else {
+        return Route::NoTunnel;
+    };
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let tunnel = &rest[..end];
+    if tunnel.is_empty() {
+        return Route::NoTunnel;
+    }
+    let prefix = format!("{PATH_PREFIX}{tunnel}");
+    if rest[end..].starts_with('/') {
+        Route::Prefixed {
+            tunnel: tunnel.to_ascii_lowercase(),
+            prefix,
+        }
+    } else {
+        Route::Redirect(format!("{prefix}/{}", &rest[end..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{DEFAULT_MAX_HEAD_SIZE, parse_request};
+
+    fn route(router: &Router, host: &str) -> Route {
+        let raw = if host.is_empty() {
+            "GET / HTTP/1.1\r\n\r\n".to_string()
+        } else {
+            format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n")
+        };
+        let (head, _) = parse_request(raw.as_bytes(), DEFAULT_MAX_HEAD_SIZE)
+            .unwrap()
+            .unwrap();
+        router.route(&head)
+    }
+
+    fn tunnel(name: &str) -> Route {
+        Route::Tunnel(name.to_string())
+    }
+
+    #[test]
+    fn test_route_under_base_domains() {
+        let router = Router::new(
+            &["example.com".to_string(), "Example.NET.".to_string()],
+            false,
+        );
+        assert_eq!(route(&router, "myapp.example.com"), tunnel("myapp"));
+        assert_eq!(route(&router, "MyApp.example.net:8443"), tunnel("myapp"));
+        assert_eq!(route(&router, "api.myapp.example.com"), tunnel("myapp"));
+        assert_eq!(route(&router, "myapp.example.com."), tunnel("myapp"));
+    }
+
+    #[test]
+    fn test_route_longest_base_domain_wins() {
+        let router = Router::new(
+            &["example.com".to_string(), "tunnel.example.com".to_string()],
+            false,
+        );
+        assert_eq!(route(&router, "myapp.tunnel.example.com"), tunnel("myapp"));
+        assert_eq!(route(&router, "tunnel.example.com"), Route::NoTunnel);
+    }
+
+    #[test]
+    fn test_route_rejects_other_hosts() {
+        let router = Router::new(&["example.com".to_string()], false);
+        assert_eq!(route(&router, "example.com"), Route::NoTunnel);
+        assert_eq!(route(&router, "a..example.com"), Route::NoTunnel);
+        assert_eq!(route(&router, "myapp.example.org"), Route::Misdirected);
+        assert_eq!(route(&router, "myappexample.com"), Route::Misdirected);
+        assert_eq!(route(&router, "localhost:8080"), Route::Misdirected);
+        assert_eq!(route(&router, ""), Route::NoTunnel);
+    }
+
+    #[test]
+    fn test_route_without_base_domains() {
+        let router = Router::default();
+        assert_eq!(route(&router, "myapp.example.com"), tunnel("myapp"));
+        assert_eq!(route(&router, "MyApp.localhost:8080"), tunnel("myapp"));
+        assert_eq!(route(&router, "localhost:8080"), Route::NoTunnel);
+        assert_eq!(route(&router, "127.0.0.1:8080"), Route::NoTunnel);
+        assert_eq!(route(&router, "a..example.com"), Route::NoTunnel);
+    }
+
+    #[test]
+    fn test_nested_host_routes_the_same_either_way() {
+        let configured = Router::new(&["example.com".to_string()], false);
+        for router in [&configured, &Router::default()] {
+            assert_eq!(route(router, "api.myapp.example.com"), tunnel("myapp"));
+            assert_eq!(route(router, "v2.api.myapp.example.com"), tunnel("myapp"));
+            assert_eq!(router.route_host("api.myapp.example.com"), tunnel("myapp"));
+        }
+    }
+
+    #[test]
+    fn test_route_host() {
+        let router = Router::new(&["example.com".to_string()], true);
+        // path routing only applies to requests, a bare host still routes by name
+        assert_eq!(router.route_host("MyApp.example.com"), tunnel("myapp"));
+        assert_eq!(router.route_host("example.com"), Route::NoTunnel);
+        assert_eq!(router.route_host("myapp.example.org"), Route::Misdirected);
+        assert_eq!(Router::default().route_host("myapp.local"), tunnel("myapp"));
+        assert_eq!(Router::default().route_host("localhost"), Route::NoTunnel);
+    }
+
+    #[test]
+    fn test_route_by_path() {
+        let router = Router::new(&["example.com".to_string()], true);
+        let route = |target: &str| {
+            let raw = format!("GET {target} HTTP/1.1\r\nHost: localhost:8080\r\n\r\n");
+            let (head, _) = parse_request(raw.as_bytes(), DEFAULT_MAX_HEAD_SIZE)
+                .unwrap()
+                .unwrap();
+            router.route(&head)
+        };
+        let prefixed = |tunnel: &str, prefix: &str| Route::Prefixed {
+            tunnel: tunnel.to_string(),
+            prefix: prefix.to_string(),
+        };
+        assert_eq!(route("/t/myapp/"), prefixed("myapp", "/t/myapp"));
+        assert_eq!(route("/t/MyApp/api/x?y=1"), prefixed("myapp", "/t/MyApp"));
+        assert_eq!(route("/t/myapp"), Route::Redirect("/t/myapp/".to_string()));
+        assert_eq!(
+            route("/t/myapp?y=1"),
+            Route::Redirect("/t/myapp/?y=1".to_string())
+        );
+        assert_eq!(route("/t/"), Route::NoTunnel);
+        assert_eq!(route("/"), Route::NoTunnel);
+        assert_eq!(route("/tunnel/myapp/"), Route::NoTunnel);
+    }
+}
@@ -1,105 +1,382 @@
+use crate::capture::Captures;
+use crate::config::is_valid_domain;
+use crate::custom_domain::CustomDomains;
+use crate::metrics::Metrics;
+use crate::registry::SubdomainRegistry;
+use crate::reservation::Reservations;
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tokio::sync::mpsc;
 
+// how often the pending streams are checked for leaked entries
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+// numbers every stream, so a name never comes back while the server runs
+static NEXT_STREAM: AtomicU64 = AtomicU64::new(1);
+
+/// A name for a new stream of `client_id`, `kind` tells what it carries (`tx`, `tcp`, ...).
+pub fn stream_name(client_id: &str, kind: &str) -> String {
+    let number = NEXT_STREAM.fetch_add(1, Ordering::Relaxed);
+    format!("{client_id}_{kind}-{number}")
+}
+
+/// `name` as a single lowercase label under the base domains, `None` if it cannot be one.
+pub fn normalize_subdomain(name: &str) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    (is_valid_domain(&name) && !name.contains('.')).then_some(name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamMode {
+    // a request and its response, the stream ends with the response
+    Http,
+    // an HTTP upgrade, after a 101 response bytes flow both ways until either side closes
+    Upgrade,
+    // a connection to a raw TCP tunnel, bytes flow both ways from the start
+    Raw,
+    // a UDP peer of a UDP tunnel, each chunk is one datagram
+    Datagram,
+}
+
 pub struct TicketRequestHttp {
     pub name: String,
+    pub mode: StreamMode,
+    // a response to HEAD has headers only, whatever they announce
+    pub head_request: bool,
+    // the request head and whatever body bytes arrived with it
     pub data: Vec<u8>,
+    // the rest of the request body, streamed while the tunnel forwards it
+    pub body: Option<mpsc::Receiver<Vec<u8>>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ResponseEvent {
+    // more response bytes, the first event carries the status line and headers
+    Data(Vec<u8>),
+    // the response is complete
+    End,
+    // the tunnel went away before the response was complete
+    Abort,
+}
+
+/// A connected tunnel as seen from outside its connection task.
+pub struct TunnelInfo {
+    // changes when an admin renames the tunnel
+    name: std::sync::RwLock<String>,
+    // who authenticated, the token or certificate name
+    pub owner: String,
+    pub peer: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    // frame bytes read from and written to the client
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    // streams waiting for or receiving a response
+    pub in_flight: AtomicUsize,
+    // requests not yet opened as streams, and frames not yet written to the client
+    pub ticket_queue: AtomicUsize,
+    pub frame_queue: AtomicUsize,
+}
+
+impl TunnelInfo {
+    pub fn new(name: &str, owner: &str, peer: SocketAddr) -> Self {
+        Self {
+            name: std::sync::RwLock::new(name.to_string()),
+            owner: owner.to_string(),
+            peer,
+            connected_at: Utc::now(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            ticket_queue: AtomicUsize::new(0),
+            frame_queue: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.read().unwrap().clone()
+    }
+}
+
+pub struct PendingEntry {
+    pub tx_http: mpsc::Sender<ResponseEvent>,
+    pub since: Instant,
+}
+
+type PendingMap = HashMap<String, PendingEntry>;
+
+/// A stream's entry in `http_connections`, removed when this is dropped, however the stream ends.
+pub struct PendingRequest {
+    name: String,
+    connections: Arc<std::sync::Mutex<PendingMap>>,
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().remove(&self.name);
+    }
+}
+
+pub struct TunnelHandle {
+    pub tx_ticket: mpsc::UnboundedSender<TicketRequestHttp>,
+    pub info: Arc<TunnelInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenameError {
+    NotFound,
+    InvalidName,
+    Taken,
+}
+
+impl fmt::Display for RenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenameError::NotFound => write!(f, "no such tunnel"),
+            RenameError::InvalidName => write!(f, "not a valid subdomain"),
+            RenameError::Taken => write!(f, "name is reserved or in use"),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct SharedState {
-    pub tcp_connections: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<TicketRequestHttp>>>>,
-    pub http_connections: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Vec<u8>>>>>,
-    pub subdomains: Vec<String>,
+    pub tcp_connections: Arc<Mutex<HashMap<String, TunnelHandle>>>,
+    pub http_connections: Arc<std::sync::Mutex<PendingMap>>,
+    pub registry: SubdomainRegistry,
+    pub reservations: Reservations,
+    pub custom_domains: CustomDomains,
+    pub metrics: Metrics,
+    pub captures: Captures,
 }
 
 impl SharedState {
-    pub fn new() -> Self {
-        let state = SharedState {
+    pub fn new(
+        reconnect_grace: Duration,
+        custom_domains: CustomDomains,
+        captures: Captures,
+    ) -> Self {
+        SharedState {
             tcp_connections: Arc::new(Mutex::new(HashMap::new())),
-            http_connections: Arc::new(Mutex::new(HashMap::new())),
-            subdomains: Vec::new(),
-        };
-        state
+            http_connections: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            registry: SubdomainRegistry::new(reconnect_grace),
+            reservations: Reservations::new(),
+            custom_domains,
+            metrics: Metrics::default(),
+            captures,
+        }
     }
 
     pub async fn send_to_tcp_client(&self, client_id: &str, ticket: TicketRequestHttp) -> bool {
         let connections = self.tcp_connections.lock().await;
-        if let Some(tx_tcp) = connections.get(client_id) {
-            tx_tcp.send(ticket).is_ok()
+        if let Some(tunnel) = connections.get(client_id) {
+            tunnel.tx_ticket.send(ticket).is_ok()
         } else {
             false
         }
     }
 
-    pub async fn send_to_http_client(&self, client_id: &str, message: Vec<u8>) -> bool {
-        let connections = self.http_connections.lock().await;
-        if let Some(tx_http) = connections.get(client_id) {
-            match tx_http.send(message) {
-                Ok(_) => true,
-                Err(e) => {
-                    eprintln!("Failed to send message to HTTP client: {e}");
-                    false
-                }
-            }
-        } else {
+    pub fn http_sender(&self, client_id: &str) -> Option<mpsc::Sender<ResponseEvent>> {
+        let connections = self.http_connections.lock().unwrap();
+        let tx_http = connections
+            .get(client_id)
+            .map(|entry| entry.tx_http.clone());
+        if tx_http.is_none() {
             tracing::error!("cannot connect http client id {client_id}");
-            false
         }
+        tx_http
     }
 
     pub async fn register_tcp_client(
         &self,
-        client_id: String,
         tx: mpsc::UnboundedSender<TicketRequestHttp>,
+        info: Arc<TunnelInfo>,
     ) {
         let mut connections = self.tcp_connections.lock().await;
-        connections.insert(client_id, tx);
+        let tunnel = TunnelHandle {
+            tx_ticket: tx,
+            info,
+        };
+        let name = tunnel.info.name();
+        self.captures.open(&name);
+        connections.insert(name, tunnel);
+    }
+
+    /// Whoever takes a tunnel out of `tcp_connections` releases its names, so a tunnel
+    /// that was already disconnected leaves a newer one under the same name alone.
+    pub async fn unregister_tcp_client(&self, info: &Arc<TunnelInfo>) {
+        let mut connections = self.tcp_connections.lock().await;
+        let name = info.name();
+        if connections
+            .get(&name)
+            .is_some_and(|tunnel| Arc::ptr_eq(&tunnel.info, info))
+        {
+            connections.remove(&name);
+            self.registry.release(&name);
+            self.custom_domains.release(&name);
+            self.captures.close(&name);
+        }
+    }
+
+    pub async fn tunnels(&self) -> Vec<Arc<TunnelInfo>> {
+        let connections = self.tcp_connections.lock().await;
+        let mut tunnels: Vec<_> = connections
+            .values()
+            .map(|tunnel| tunnel.info.clone())
+            .collect();
+        tunnels.sort_by_key(|info| info.name());
+        tunnels
     }
-    pub async fn unregister_tcp_client(&self, client_id: &str) {
+
+    /// Drops a tunnel from the outside, its connection task sees the closed channel and ends.
+    pub async fn disconnect_tunnel(&self, name: &str) -> bool {
         let mut connections = self.tcp_connections.lock().await;
-        connections.remove(client_id);
+        if connections.remove(name).is_none() {
+            return false;
+        }
+        self.registry.release(name);
+        self.custom_domains.release(name);
+        self.captures.close(name);
+        true
     }
 
-    pub async fn register_http_client(
+    /// Moves a tunnel to another subdomain, the resume token and custom domains go with it.
+    pub async fn rename_tunnel(
         &self,
-        client_id: String,
-        tx: mpsc::UnboundedSender<Vec<u8>>,
-    ) {
-        let mut connections = self.http_connections.lock().await;
-
-        connections.insert(client_id, tx);
+        name: &str,
+        new_name: &str,
+    ) -> Result<Arc<TunnelInfo>, RenameError> {
+        let new_name = normalize_subdomain(new_name).ok_or(RenameError::InvalidName)?;
+        let mut connections = self.tcp_connections.lock().await;
+        if !connections.contains_key(name) {
+            return Err(RenameError::NotFound);
+        }
+        if self.reservations.is_reserved(&new_name) || !self.registry.rename(name, &new_name) {
+            return Err(RenameError::Taken);
+        }
+        let tunnel = connections.remove(name).ok_or(RenameError::NotFound)?;
+        *tunnel.info.name.write().unwrap() = new_name.clone();
+        self.custom_domains.reassign(name, &new_name);
+        self.captures.rename(name, &new_name);
+        let info = tunnel.info.clone();
+        connections.insert(new_name, tunnel);
+        Ok(info)
     }
-    pub fn check_duplicate_subdomain(&mut self, subdomain: String) -> bool {
-        let dup = self.subdomains.contains(&subdomain);
-        if dup == false {
-            self.subdomains.push(subdomain);
 
-            false
-        } else {
-            true
+    /// Makes a stream reachable for the tunnel's responses until the returned guard is dropped.
+    pub fn register_http_client(
+        &self,
+        name: String,
+        tx_http: mpsc::Sender<ResponseEvent>,
+    ) -> PendingRequest {
+        let entry = PendingEntry {
+            tx_http,
+            since: Instant::now(),
+        };
+        let mut connections = self.http_connections.lock().unwrap();
+        connections.insert(name.clone(), entry);
+        PendingRequest {
+            name,
+            connections: self.http_connections.clone(),
         }
     }
+
+    /// Names of the streams still waiting on a tunnel.
+    pub fn pending_requests(&self) -> Vec<String> {
+        let connections = self.http_connections.lock().unwrap();
+        let mut names: Vec<String> = connections.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Drops entries nobody receives on anymore. Their guard should have removed them,
+    /// so each one is a leak and gets logged.
+    pub fn reap_pending(&self) -> usize {
+        let mut connections = self.http_connections.lock().unwrap();
+        let before = connections.len();
+        connections.retain(|name, entry| {
+            let leaked = entry.tx_http.is_closed();
+            if leaked {
+                tracing::warn!(
+                    "reaped leaked stream {name} after {:?}",
+                    entry.since.elapsed()
+                );
+            }
+            !leaked
+        });
+        before - connections.len()
+    }
+
+    pub fn spawn_reaper(&self) {
+        let shared_state = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                shared_state.reap_pending();
+            }
+        });
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn state() -> SharedState {
+        SharedState::new(
+            Duration::from_secs(30),
+            CustomDomains::new(&[], false, ([127, 0, 0, 1], 53).into()),
+            Captures::new(0, 0),
+        )
+    }
+
     #[test]
-    fn test_check_duplicate_subdomain() {
-        let mut shared_state = SharedState::new();
-        let subdomain = "example";
+    fn test_stream_names_are_unique() {
+        let first = stream_name("myapp", "tx");
+        let second = stream_name("myapp", "tx");
+        assert!(first.starts_with("myapp_tx-"));
+        assert_ne!(first, second);
+    }
 
+    #[test]
+    fn test_normalize_subdomain() {
+        assert_eq!(normalize_subdomain("MyApp"), Some("myapp".to_string()));
         assert_eq!(
-            shared_state.check_duplicate_subdomain(subdomain.to_string()),
-            false
-        );
-        assert_eq!(
-            shared_state.check_duplicate_subdomain(subdomain.to_string()),
-            true
+            normalize_subdomain("my-app-2"),
+            Some("my-app-2".to_string())
         );
+        assert_eq!(normalize_subdomain("api.myapp"), None);
+        assert_eq!(normalize_subdomain("my app"), None);
+        assert_eq!(normalize_subdomain(""), None);
+    }
+
+    #[test]
+    fn test_pending_request_guard() {
+        let shared_state = state();
+        let (tx_http, _rx_http) = mpsc::channel(1);
+        let pending = shared_state.register_http_client("myapp_tx-1".to_string(), tx_http);
+        assert!(shared_state.http_sender("myapp_tx-1").is_some());
+        drop(pending);
+        assert!(shared_state.pending_requests().is_empty());
+    }
+
+    #[test]
+    fn test_reap_pending() {
+        let shared_state = state();
+        let (tx_http, _rx_http) = mpsc::channel(1);
+        let _live = shared_state.register_http_client("myapp_tx-1".to_string(), tx_http);
+        let (tx_http, rx_http) = mpsc::channel(1);
+        // a guard that is never dropped
+        std::mem::forget(shared_state.register_http_client("myapp_tx-2".to_string(), tx_http));
+        drop(rx_http);
+
+        assert_eq!(shared_state.reap_pending(), 1);
+        assert_eq!(shared_state.pending_requests(), ["myapp_tx-1"]);
     }
 }
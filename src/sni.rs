@@ -0,0 +1,267 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{Limits, is_valid_domain};
+use crate::routing::{Route, Router};
+use crate::shared::SharedState;
+use crate::tcp_tunnel;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST: u8 = 0x00;
+// the largest record a client may send, plus room for compression
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+// a client that does not say hello by then is dropped
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniError {
+    NotTls,
+    MalformedHello,
+    NoServerName,
+}
+
+impl fmt::Display for SniError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SniError::NotTls => write!(f, "not a TLS ClientHello"),
+            SniError::MalformedHello => write!(f, "malformed ClientHello"),
+            SniError::NoServerName => write!(f, "ClientHello without a server name"),
+        }
+    }
+}
+
+impl std::error::Error for SniError {}
+
+/// The server name a ClientHello asks for, `Ok(None)` until the first record is complete.
+/// Only the first record is looked at, a hello split over several records has no name.
+pub fn parse_sni(data: &[u8]) -> Result<Option<String>, SniError> {
+    if data.len() < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    if data[0] != CONTENT_HANDSHAKE {
+        return Err(SniError::NotTls);
+    }
+    let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if record_len > MAX_RECORD_LEN {
+        return Err(SniError::NotTls);
+    }
+    let Some(record) = data.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len) else {
+        return Ok(None);
+    };
+
+    let mut hello = Reader(record);
+    if hello.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return Err(SniError::NotTls);
+    }
+    let hello_len = hello.u24()?;
+    let mut hello = Reader(hello.bytes(hello_len).map_err(|_| SniError::NoServerName)?);
+    // version and random
+    hello.bytes(2 + 32)?;
+    let session_id_len = hello.u8()? as usize;
+    hello.bytes(session_id_len)?;
+    let cipher_suites_len = hello.u16()? as usize;
+    hello.bytes(cipher_suites_len)?;
+    let compression_len = hello.u8()? as usize;
+    hello.bytes(compression_len)?;
+    if hello.is_empty() {
+        return Err(SniError::NoServerName);
+    }
+
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.bytes(extensions_len)?);
+    while !extensions.is_empty() {
+        let extension = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let data = extensions.bytes(extension_len)?;
+        if extension == EXTENSION_SERVER_NAME {
+            return server_name(data).map(Some);
+        }
+    }
+    Err(SniError::NoServerName)
+}
+
+fn server_name(extension: &[u8]) -> Result<String, SniError> {
+    let mut extension = Reader(extension);
+    let list_len = extension.u16()? as usize;
+    let mut names = Reader(extension.bytes(list_len)?);
+    while !names.is_empty() {
+        let name_type = names.u8()?;
+        let name_len = names.u16()? as usize;
+        let name = names.bytes(name_len)?;
+        if name_type != NAME_TYPE_HOST {
+            continue;
+        }
+        let name = std::str::from_utf8(name)
+            .map_err(|_| SniError::MalformedHello)?
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        if !is_valid_domain(&name) {
+            return Err(SniError::MalformedHello);
+        }
+        return Ok(name);
+    }
+    Err(SniError::NoServerName)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SniError> {
+        if self.0.len() < len {
+            return Err(SniError::MalformedHello);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, SniError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SniError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, SniError> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Accepts TLS connections without terminating them, the SNI picks the tunnel
+/// and the encrypted bytes go through it untouched.
+pub struct SniServer {
+    listener: TcpListener,
+    shared_state: SharedState,
+    router: Router,
+    limits: Limits,
+}
+
+impl SniServer {
+    pub async fn new(
+        addr: SocketAddr,
+        shared_state: SharedState,
+        router: Router,
+        limits: Limits,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(SniServer {
+            listener,
+            shared_state,
+            router,
+            limits,
+        })
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            let (socket, addr) = self.listener.accept().await?;
+            let shared_state = self.shared_state.clone();
+            let router = self.router.clone();
+            let limits = self.limits.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle_connection(socket, shared_state, router, limits).await
+                {
+                    tracing::debug!("passthrough connection from {addr} dropped: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        mut socket: TcpStream,
+        shared_state: SharedState,
+        router: Router,
+        limits: Limits,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut hello = Vec::new();
+        let server_name = tokio::time::timeout(HELLO_TIMEOUT, read_sni(&mut socket, &mut hello))
+            .await
+            .map_err(|_| "no ClientHello in time")??;
+
+        let client_id = match shared_state.custom_domains.lookup(&server_name) {
+            Some(client_id) => client_id,
+            None => match router.route_host(&server_name) {
+                Route::Tunnel(name) => name,
+                // there is nobody to answer the TLS handshake, closing is all we can do
+                _ => return Err(format!("no tunnel for {server_name}").into()),
+            },
+        };
+        tracing::info!("passthrough [{client_id}] for {server_name}");
+        // the hello goes first, the local server does the handshake itself
+        tcp_tunnel::forward(socket, client_id, shared_state, limits, hello).await;
+        Ok(())
+    }
+}
+
+// reads into `hello` until the first record is in, the bytes are passed on as they are
+async fn read_sni(socket: &mut TcpStream, hello: &mut Vec<u8>) -> Result<String, SniError> {
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(name) = parse_sni(hello)? {
+            return Ok(name);
+        }
+        match socket.read(&mut buf).await {
+            Ok(n) if n > 0 => hello.extend_from_slice(&buf[..n]),
+            _ => return Err(SniError::MalformedHello),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::RootCertStore;
+    use rustls::crypto::ring;
+    use rustls::pki_types::ServerName;
+    use std::sync::Arc;
+
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let config =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth();
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let mut connection = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = Vec::new();
+        connection.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let hello = client_hello("MyApp.example.com");
+        assert_eq!(parse_sni(&hello), Ok(Some("myapp.example.com".to_string())));
+        // nothing is decided before the first record is complete
+        assert_eq!(parse_sni(&hello[..3]), Ok(None));
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_sni_errors() {
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), Err(SniError::NotTls));
+        // rustls leaves the name out for IP addresses
+        let hello = client_hello("127.0.0.1");
+        assert_eq!(parse_sni(&hello), Err(SniError::NoServerName));
+
+        let mut truncated = client_hello("myapp.example.com");
+        let record_len = (truncated.len() - RECORD_HEADER_LEN - 8) as u16;
+        truncated[3..5].copy_from_slice(&record_len.to_be_bytes());
+        truncated.truncate(RECORD_HEADER_LEN + record_len as usize);
+        assert!(parse_sni(&truncated).is_err());
+    }
+}
@@ -1,32 +1,73 @@
-use crate::shared::SharedState;
+use crate::auth::{Authenticator, Identity};
+use crate::body::{BodyFraming, BodyTracker};
+use crate::codec::{
+    CodecError, ErrorCode, ErrorMessage, Handshake, HandshakeAck, PROTOCOL_VERSION, decode_frame,
+};
+use crate::config::Limits;
+use crate::custom_domain;
+use crate::frame::{FLAG_DATAGRAM, FLAG_RAW, Frame, FrameType, read_frame, write_frame};
+use crate::http::parse_response;
+use crate::port_pool::{PortLease, PortPool};
+use crate::reservation::ReservationCheck;
+use crate::shared::{SharedState, TunnelInfo, normalize_subdomain};
+use crate::tcp_tunnel;
+use crate::udp_tunnel;
 use rand::Rng;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::str;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::select;
+use tokio::sync::Notify;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio_rustls::TlsAcceptor;
 
-use crate::shared::TicketRequestHttp;
+use crate::shared::{ResponseEvent, StreamMode, TicketRequestHttp};
 
 pub struct TcpServer {
     listener: TcpListener,
     shared_state: SharedState,
+    authenticator: Arc<dyn Authenticator>,
+    // set when clients connect over TLS, the config decides whether they need a certificate
+    tls: Option<TlsAcceptor>,
+    settings: TunnelSettings,
 }
 
-const MINIMUM_CLIENT_VERSION: &str = "0.0.2";
-const TWO_DELIMETER_BYTES: &[u8] = b"\r\n\r\n";
-const ZERO_DELIMETER_BYTES: &[u8] = b"0\r\n\r\n";
+/// What every tunnel connection is checked against and set up with.
+#[derive(Clone)]
+pub struct TunnelSettings {
+    pub minimum_client_version: String,
+    pub limits: Limits,
+    // public ports for raw TCP and UDP tunnels, `None` when they are turned off
+    pub tcp_ports: Option<PortPool>,
+    pub udp_ports: Option<PortPool>,
+    // a UDP peer without traffic for this long loses its session
+    pub udp_session_idle: Duration,
+    pub udp_max_sessions: usize,
+    // a client that connects and stays silent is dropped after it
+    pub handshake_timeout: Duration,
+}
 
 impl TcpServer {
     pub async fn new(
-        addr: &str,
+        addr: SocketAddr,
         shared_state: SharedState,
+        authenticator: Arc<dyn Authenticator>,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        settings: TunnelSettings,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(addr).await?;
         Ok(TcpServer {
             listener,
             shared_state,
+            authenticator,
+            tls: tls_config.map(TlsAcceptor::from),
+            settings,
         })
     }
 
@@ -36,163 +77,749 @@ impl TcpServer {
             tracing::info!("New TCP connection from: {addr}");
 
             let shared_state = self.shared_state.clone();
+            let authenticator = self.authenticator.clone();
+            let tls = self.tls.clone();
+            let settings = self.settings.clone();
 
             // Spawn a new task for each TCP connection
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_tcp_connection(socket, shared_state).await {
+                let result = match tls {
+                    Some(acceptor) => match acceptor.accept(socket).await {
+                        Ok(stream) => {
+                            // only present when the certificate passed the client CA check
+                            let peer = stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(crate::tls::peer_identity);
+                            Self::handle_tcp_connection(
+                                stream,
+                                addr,
+                                peer,
+                                shared_state,
+                                authenticator,
+                                settings,
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            tracing::warn!("TLS handshake from {addr} failed: {e}");
+                            return;
+                        }
+                    },
+                    None => {
+                        Self::handle_tcp_connection(
+                            socket,
+                            addr,
+                            None,
+                            shared_state,
+                            authenticator,
+                            settings,
+                        )
+                        .await
+                    }
+                };
+                if let Err(e) = result {
                     eprintln!("Error handling TCP connection: {e}");
                 }
             });
         }
     }
 
-    async fn handle_tcp_connection(
-        mut stream: TcpStream,
-        mut shared_state: SharedState,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // check version available
-        let mut first_access = [0u8; 4096];
-        let n = stream.read(&mut first_access).await?;
-        if n == 0 {
-            return Ok(());
-        }
+    async fn handle_tcp_connection<S>(
+        mut stream: S,
+        addr: SocketAddr,
+        peer: Option<Identity>,
+        shared_state: SharedState,
+        authenticator: Arc<dyn Authenticator>,
+        settings: TunnelSettings,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let handshake = read_handshake(&mut stream, &settings.minimum_client_version);
+        let handshake = match tokio::time::timeout(settings.handshake_timeout, handshake).await {
+            Ok(Ok(Some(handshake))) => handshake,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(error)) => return reject(&mut stream, addr, error, &shared_state).await,
+            Err(_) => {
+                let error =
+                    ErrorMessage::new(ErrorCode::HandshakeTimeout, "no handshake received in time");
+                return reject(&mut stream, addr, error, &shared_state).await;
+            }
+        };
 
-        let incoming_message = String::from_utf8_lossy(&first_access[..n]);
-        if let Some(version) = incoming_message.split(" ").nth(1) {
-            if !check_available_version(&version, MINIMUM_CLIENT_VERSION) {
-                let txt_resp = "ERR001:request_higher_version";
-                stream.write_all(txt_resp.as_bytes()).await?;
-                return Ok(());
+        let authenticated = match peer {
+            // a verified client certificate already names the tenant
+            Some(identity) => Ok(identity),
+            None => authenticator.authenticate(handshake.token.as_deref()),
+        };
+        let identity = match authenticated {
+            Ok(identity) => identity,
+            Err(e) => {
+                tracing::warn!("authentication failed from {addr}: {e}");
+                let error = ErrorMessage::new(ErrorCode::Unauthorized, &e.to_string());
+                return reject(&mut stream, addr, error, &shared_state).await;
             }
-        }
-        let mut client_id;
-        if let Some(sub_domain_name) = incoming_message.split(" ").nth(2) {
-            client_id = sub_domain_name.to_string();
-            let mut cnt = 1;
-            while shared_state.check_duplicate_subdomain(client_id.clone()) {
-                client_id = format!("{client_id}-{cnt}");
-                cnt += 1;
+        };
+
+        let reservations = shared_state.reservations.clone();
+        let registry = shared_state.registry.clone();
+        let token = handshake.token.as_deref();
+
+        // a client coming back within the grace period gets its previous name again
+        let resumed = handshake
+            .resume_token
+            .as_deref()
+            .and_then(|resume_token| registry.resume(resume_token))
+            .map(|name| (name, handshake.resume_token.clone().unwrap_or_default()));
+
+        let (client_id, resume_token) = if let Some((name, resume_token)) = resumed {
+            if reservations.check(&name, token) == ReservationCheck::Taken {
+                registry.release(&name);
+                let error = ErrorMessage::new(
+                    ErrorCode::SubdomainReserved,
+                    &format!("subdomain {name} is reserved"),
+                );
+                return reject(&mut stream, addr, error, &shared_state).await;
+            }
+            tracing::info!("client resumed subdomain [{name}]");
+            (name, resume_token)
+        } else if let Some(requested) = handshake.subdomain {
+            // routing lowercases the host, and a dot would make it a nested host
+            let Some(sub_domain_name) = normalize_subdomain(&requested) else {
+                let error = ErrorMessage::new(
+                    ErrorCode::InvalidSubdomain,
+                    &format!("{requested:?} is not a valid subdomain"),
+                );
+                return reject(&mut stream, addr, error, &shared_state).await;
+            };
+            match reservations.check(&sub_domain_name, token) {
+                ReservationCheck::Owner => {
+                    // a reserved name is never renamed, the owner has to free it first
+                    let Some(resume_token) = registry.claim(&sub_domain_name) else {
+                        let error = ErrorMessage::new(
+                            ErrorCode::SubdomainInUse,
+                            &format!("subdomain {sub_domain_name} is already connected"),
+                        );
+                        return reject(&mut stream, addr, error, &shared_state).await;
+                    };
+                    (sub_domain_name, resume_token)
+                }
+                ReservationCheck::Taken => {
+                    let error = ErrorMessage::new(
+                        ErrorCode::SubdomainReserved,
+                        &format!("subdomain {sub_domain_name} is reserved"),
+                    );
+                    return reject(&mut stream, addr, error, &shared_state).await;
+                }
+                ReservationCheck::Free => {
+                    let mut client_id = sub_domain_name.clone();
+                    let mut cnt = 1;
+                    loop {
+                        if !reservations.is_reserved(&client_id)
+                            && let Some(resume_token) = registry.claim(&client_id)
+                        {
+                            break (client_id, resume_token);
+                        }
+                        client_id = format!("{sub_domain_name}-{cnt}");
+                        cnt += 1;
+                    }
+                }
             }
         } else {
-            client_id = generate_name();
-            while shared_state.check_duplicate_subdomain(client_id.clone()) {
-                client_id = generate_name();
+            loop {
+                let client_id = generate_name();
+                if !reservations.is_reserved(&client_id)
+                    && let Some(resume_token) = registry.claim(&client_id)
+                {
+                    break (client_id, resume_token);
+                }
+            }
+        };
+        tracing::info!("client id [{client_id}] for {} from {addr}", identity.name);
+
+        let custom_domains = shared_state.custom_domains.clone();
+        let mut domain_challenges = Vec::new();
+        for domain in &handshake.custom_domains {
+            match custom_domains.claim(domain, &identity.name, &client_id) {
+                Ok(Some(token)) => domain_challenges.push((domain.clone(), token)),
+                Ok(None) => tracing::info!("custom domain {domain} routed to [{client_id}]"),
+                Err(message) => {
+                    custom_domains.release(&client_id);
+                    registry.release(&client_id);
+                    let error = ErrorMessage::new(ErrorCode::DomainUnavailable, &message);
+                    return reject(&mut stream, addr, error, &shared_state).await;
+                }
             }
         }
-        tracing::info!("client id [{client_id}]");
+
+        let (tcp_tunnel, udp_tunnel) =
+            match bind_tunnel_ports(handshake.tcp_port, handshake.udp_port, &settings).await {
+                Ok(bound) => bound,
+                Err(error) => {
+                    custom_domains.release(&client_id);
+                    registry.release(&client_id);
+                    return reject(&mut stream, addr, error, &shared_state).await;
+                }
+            };
         let (tx_tcp, mut rx_tcp) = mpsc::unbounded_channel::<TicketRequestHttp>();
-        shared_state
-            .register_tcp_client(client_id.to_string(), tx_tcp)
-            .await;
+        let info = Arc::new(TunnelInfo::new(&client_id, &identity.name, addr));
+        shared_state.register_tcp_client(tx_tcp, info.clone()).await;
+
+        let ack = HandshakeAck {
+            client_id: client_id.clone(),
+            resume_token: Some(resume_token),
+            domain_challenges: domain_challenges.clone(),
+            tcp_port: tcp_tunnel.as_ref().map(|(_, lease)| lease.port()),
+            udp_port: udp_tunnel.as_ref().map(|(_, lease)| lease.port()),
+        };
+        if let Err(e) = write_frame(&mut stream, &Frame::ack(&ack)).await {
+            shared_state.unregister_tcp_client(&info).await;
+            return Err(e.into());
+        }
+        // the client knows the tokens from the ack, its owner puts them in DNS
+        for (domain, token) in domain_challenges {
+            tokio::spawn(custom_domain::verify(
+                shared_state.clone(),
+                client_id.clone(),
+                identity.name.clone(),
+                domain,
+                token,
+            ));
+        }
+        // the public port closes and goes back to the pool when the tunnel ends
+        let tcp_tunnel_task = tcp_tunnel.map(|(listener, lease)| {
+            tracing::info!("tcp tunnel [{client_id}] on port {}", lease.port());
+            tokio::spawn(tcp_tunnel::serve(
+                listener,
+                lease,
+                info.clone(),
+                shared_state.clone(),
+                settings.limits.clone(),
+            ))
+        });
+        let udp_tunnel_task = udp_tunnel.map(|(socket, lease)| {
+            tracing::info!("udp tunnel [{client_id}] on port {}", lease.port());
+            tokio::spawn(udp_tunnel::serve(
+                socket,
+                lease,
+                info.clone(),
+                shared_state.clone(),
+                settings.limits.clone(),
+                settings.udp_session_idle,
+                settings.udp_max_sessions,
+            ))
+        });
+
+        // every request gets its own stream id, so many requests can share the connection
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        let (tx_frame, mut rx_frame) =
+            mpsc::channel::<Frame>(settings.limits.frame_channel_capacity);
+        let writer_info = info.clone();
+        let writer_task = tokio::spawn(async move {
+            while let Some(frame) = rx_frame.recv().await {
+                if let Err(e) = write_frame(&mut writer, &frame).await {
+                    eprintln!("Error writing frame to TCP client: {e}");
+                    break;
+                }
+                writer_info
+                    .bytes_sent
+                    .fetch_add(frame.payload.len() as u64, Ordering::Relaxed);
+                writer_info
+                    .frame_queue
+                    .store(rx_frame.len(), Ordering::Relaxed);
+            }
+        });
+
+        // a full queue stops reading from the client until the loop below catches up
+        let (tx_incoming, mut rx_incoming) =
+            mpsc::channel::<Frame>(settings.limits.frame_channel_capacity);
+        let reader_info = info.clone();
+        let reader_task = tokio::spawn(async move {
+            let mut buffer: Vec<u8> = Vec::new();
+            let mut tmp = vec![0u8; settings.limits.read_chunk_size];
+            loop {
+                match decode_frame(&buffer) {
+                    Ok(Some((frame, used))) => {
+                        buffer.drain(..used);
+                        if tx_incoming.send(frame).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("Error reading frame from TCP client: {e}");
+                        break;
+                    }
+                }
+                match reader.read(&mut tmp).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        reader_info
+                            .bytes_received
+                            .fetch_add(n as u64, Ordering::Relaxed);
+                        buffer.extend_from_slice(&tmp[..n]);
+                    }
+                    Err(e) => {
+                        eprintln!("Error reading from TCP client: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        let (tx_closed, mut rx_closed) = mpsc::unbounded_channel::<StreamEnd>();
 
-        // Send welcome message
-        let welcome = format!("{client_id}");
-        stream.write_all(welcome.as_bytes()).await?;
+        let mut streams: HashMap<u32, TunnelStream> = HashMap::new();
+        let mut next_stream_id: u32 = 1;
 
         loop {
             select! {
                 msg = rx_tcp.recv() => {
                     match msg {
                         Some(ticket) => {
-                            process_ticket(ticket, &mut stream, &client_id, &mut shared_state).await;
+                            let stream_id = next_stream_id;
+                            next_stream_id = next_stream_id.wrapping_add(1).max(1);
+                            open_stream(ticket, stream_id, &tx_frame, &tx_closed, &mut streams, &shared_state).await;
+                        },
+                        None => break,
+                    }
+                },
+                Some(end) = rx_closed.recv() => {
+                    close_stream(end, &tx_frame, &mut streams).await;
+                },
+                frame = rx_incoming.recv() => {
+                    match frame {
+                        Some(frame) => {
+                            process_frame(frame, &tx_frame, &mut streams, &settings.limits).await;
                         },
                         None => {
-                            tracing::info!("TCP client application close: [{client_id}] ");
-                            shared_state
-                                .unregister_tcp_client(client_id.as_str()).await;
+                            tracing::info!("TCP client application close: [{}] ", info.name());
                             break;
                         }
                     }
                 },
             }
+            info.in_flight.store(streams.len(), Ordering::Relaxed);
+            info.ticket_queue.store(rx_tcp.len(), Ordering::Relaxed);
+        }
+
+        shared_state.unregister_tcp_client(&info).await;
+        for (_, tunnel_stream) in streams.drain() {
+            let _ = tunnel_stream.deliver(ResponseEvent::Abort);
+        }
+        reader_task.abort();
+        writer_task.abort();
+        for task in [tcp_tunnel_task, udp_tunnel_task].into_iter().flatten() {
+            task.abort();
         }
         Ok(())
     }
 }
 
-async fn process_ticket(
+enum StreamEnd {
+    // the public side stopped sending, for upgraded and raw connections that is the only
+    // end signal from that side
+    Finished(u32),
+    // nobody reads the responses anymore, such as a request that timed out
+    Abandoned(u32),
+}
+
+struct TunnelStream {
+    name: String,
+    mode: StreamMode,
+    // the http client's own queue, kept to see whether it is still there
+    tx_http: mpsc::Sender<ResponseEvent>,
+    // everything for the http client goes through here, a task of the stream hands it over
+    // as fast as the client takes it so a slow one holds up nobody else
+    tx_relay: mpsc::UnboundedSender<ResponseEvent>,
+    // bytes in `tx_relay` the http client has not taken yet
+    queued: Arc<AtomicUsize>,
+    drained: Arc<Notify>,
+    head_request: bool,
+    // response bytes held back until the whole head has arrived
+    head: Vec<u8>,
+    body: Option<BodyTracker>,
+    // the server already told the client it sends nothing more on this stream
+    close_sent: bool,
+}
+
+impl TunnelStream {
+    fn new(
+        name: String,
+        mode: StreamMode,
+        tx_http: mpsc::Sender<ResponseEvent>,
+        head_request: bool,
+    ) -> Self {
+        let (tx_relay, mut rx_relay) = mpsc::unbounded_channel::<ResponseEvent>();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let drained = Arc::new(Notify::new());
+        let relay_http = tx_http.clone();
+        let relay_queued = queued.clone();
+        let relay_drained = drained.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx_relay.recv().await {
+                let len = event_len(&event);
+                let sent = relay_http.send(event).await.is_ok();
+                relay_queued.fetch_sub(len, Ordering::Relaxed);
+                relay_drained.notify_one();
+                if !sent {
+                    break;
+                }
+            }
+        });
+
+        TunnelStream {
+            name,
+            mode,
+            tx_http,
+            tx_relay,
+            queued,
+            drained,
+            head_request,
+            head: Vec::new(),
+            // raw bytes and datagrams have no head to wait for
+            body: matches!(mode, StreamMode::Raw | StreamMode::Datagram)
+                .then(|| BodyTracker::new(BodyFraming::UntilClose)),
+            close_sent: false,
+        }
+    }
+
+    /// Queues an event for the http client without waiting for it to be taken.
+    fn deliver(&self, event: ResponseEvent) -> Result<(), SendError<ResponseEvent>> {
+        self.queued.fetch_add(event_len(&event), Ordering::Relaxed);
+        self.tx_relay.send(event)
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Waits while more than `limit` bytes are queued for the http client.
+    async fn wait_for_room(&self, limit: usize) {
+        while self.queued() > limit && !self.tx_http.is_closed() {
+            self.drained.notified().await;
+        }
+    }
+}
+
+fn event_len(event: &ResponseEvent) -> usize {
+    match event {
+        ResponseEvent::Data(bytes) => bytes.len(),
+        ResponseEvent::End | ResponseEvent::Abort => 0,
+    }
+}
+
+async fn open_stream(
     ticket: TicketRequestHttp,
-    stream: &mut TcpStream,
-    client_id: &str,
-    shared_state: &mut SharedState,
+    stream_id: u32,
+    tx_frame: &mpsc::Sender<Frame>,
+    tx_closed: &mpsc::UnboundedSender<StreamEnd>,
+    streams: &mut HashMap<u32, TunnelStream>,
+    shared_state: &SharedState,
 ) {
-    let message = ticket.data;
-    if let Err(e) = stream.write_all(&message).await {
-        eprintln!(
-            "Error sending direct message to TCP client {client_id}: {e}"
-        );
-        shared_state.send_to_http_client(&ticket.name, vec![]).await;
+    let Some(tx_http) = shared_state.http_sender(&ticket.name) else {
+        return;
+    };
+    let mut open = Frame::open(stream_id, ticket.data);
+    open.flags = match ticket.mode {
+        StreamMode::Raw => FLAG_RAW,
+        StreamMode::Datagram => FLAG_DATAGRAM,
+        StreamMode::Http | StreamMode::Upgrade => 0,
+    };
+    if tx_frame.send(open).await.is_err() {
+        eprintln!("Error sending request to TCP client for {}", ticket.name);
+        // nothing was sent on the stream yet, there is room for this
+        let _ = tx_http.try_send(ResponseEvent::Abort);
+        return;
     }
 
-    if let Err(e) = stream.flush().await {
-        eprintln!("Error flushing TCP stream: {e}");
-        shared_state.send_to_http_client(&ticket.name, vec![]).await;
+    // the request body keeps flowing while responses for other streams come back
+    if let Some(mut body) = ticket.body {
+        let tx_frame = tx_frame.clone();
+        let tx_closed = tx_closed.clone();
+        let mode = ticket.mode;
+        tokio::spawn(async move {
+            while let Some(chunk) = body.recv().await {
+                if tx_frame.send(Frame::data(stream_id, chunk)).await.is_err() {
+                    break;
+                }
+            }
+            if mode != StreamMode::Http {
+                let _ = tx_closed.send(StreamEnd::Finished(stream_id));
+            }
+        });
     }
 
-    let mut buffer: Vec<u8> = Vec::new();
-    let mut tmp = [0u8; 4096];
-    let header_end;
-    loop {
-        let n = stream.read(&mut tmp).await.unwrap();
-        if n == 0 {
-            eprintln!("Connection closed before headers");
+    // the stream goes away with its reader, the client stops working on it
+    let watched = tx_http.clone();
+    let tx_closed = tx_closed.clone();
+    tokio::spawn(async move {
+        watched.closed().await;
+        let _ = tx_closed.send(StreamEnd::Abandoned(stream_id));
+    });
+
+    let tunnel_stream = TunnelStream::new(ticket.name, ticket.mode, tx_http, ticket.head_request);
+    streams.insert(stream_id, tunnel_stream);
+}
+
+async fn close_stream(
+    end: StreamEnd,
+    tx_frame: &mpsc::Sender<Frame>,
+    streams: &mut HashMap<u32, TunnelStream>,
+) {
+    match end {
+        StreamEnd::Finished(stream_id) => {
+            let datagram = streams
+                .get(&stream_id)
+                .is_some_and(|tunnel_stream| tunnel_stream.mode == StreamMode::Datagram);
+            if datagram {
+                // a UDP session has nothing more to wait for
+                if let Some(tunnel_stream) = streams.remove(&stream_id) {
+                    let _ = tunnel_stream.deliver(ResponseEvent::End);
+                    let _ = tx_frame.send(Frame::close(stream_id)).await;
+                }
+            } else if let Some(tunnel_stream) = streams.get_mut(&stream_id)
+                && !tunnel_stream.close_sent
+            {
+                // a half-close, the stream stays until the client closes its side too
+                tunnel_stream.close_sent = true;
+                let _ = tx_frame.send(Frame::close(stream_id)).await;
+            }
         }
-        buffer.extend_from_slice(&tmp[..n]);
-        if let Some(pos) = buffer.windows(4).position(|w| w == TWO_DELIMETER_BYTES) {
-            header_end = pos + 4;
-            break;
+        StreamEnd::Abandoned(stream_id) => {
+            // the id may already belong to a newer stream
+            if streams
+                .get(&stream_id)
+                .is_some_and(|tunnel_stream| tunnel_stream.tx_http.is_closed())
+                && let Some(tunnel_stream) = streams.remove(&stream_id)
+            {
+                tracing::info!("stream {} abandoned by its reader", tunnel_stream.name);
+                if !tunnel_stream.close_sent {
+                    let _ = tx_frame.send(Frame::close(stream_id)).await;
+                }
+            }
         }
     }
-    let header_text = String::from_utf8_lossy(&buffer[..header_end]);
+}
+
+async fn process_frame(
+    frame: Frame,
+    tx_frame: &mpsc::Sender<Frame>,
+    streams: &mut HashMap<u32, TunnelStream>,
+    limits: &Limits,
+) {
+    match frame.frame_type {
+        FrameType::Data => {
+            let Some(tunnel_stream) = streams.get_mut(&frame.stream_id) else {
+                tracing::warn!("data for unknown stream {}", frame.stream_id);
+                return;
+            };
+
+            if tunnel_stream.mode == StreamMode::Datagram {
+                // like the network itself, drop what the peer cannot take right now
+                if tunnel_stream.queued() + frame.payload.len() <= limits.response_buffer_size {
+                    let _ = tunnel_stream.deliver(ResponseEvent::Data(frame.payload));
+                }
+                return;
+            }
 
-    let mut headers = HashMap::new();
-    for line in header_text.lines().skip(1) {
-        if let Some((k, v)) = line.split_once(": ") {
-            headers.insert(k.to_string(), v.to_string());
+            let finished = match relay_response(tunnel_stream, frame.payload, limits.max_head_size)
+            {
+                Ok(finished) => finished,
+                Err(_) => {
+                    tracing::warn!("http client {} went away", tunnel_stream.name);
+                    true
+                }
+            };
+            if !finished {
+                // only a client far behind holds up the tunnel, the others get their
+                // bytes queued
+                tunnel_stream
+                    .wait_for_room(limits.response_buffer_size)
+                    .await;
+            } else if let Some(tunnel_stream) = streams.remove(&frame.stream_id)
+                && !tunnel_stream.close_sent
+            {
+                let _ = tx_frame.send(Frame::close(frame.stream_id)).await;
+            }
+        }
+        FrameType::Close => {
+            // the client closed its side, which ends bodies that run until close
+            if let Some(tunnel_stream) = streams.remove(&frame.stream_id) {
+                let event = match &tunnel_stream.body {
+                    Some(body) if body.framing() == BodyFraming::UntilClose => ResponseEvent::End,
+                    Some(_) => ResponseEvent::Abort,
+                    None if tunnel_stream.head.is_empty() => ResponseEvent::Abort,
+                    None => {
+                        // not a full HTTP head, hand over whatever arrived
+                        let head = tunnel_stream.head.clone();
+                        let _ = tunnel_stream.deliver(ResponseEvent::Data(head));
+                        ResponseEvent::End
+                    }
+                };
+                let _ = tunnel_stream.deliver(event);
+            }
+        }
+        FrameType::Open => {
+            tracing::warn!(
+                "unexpected open frame from client for stream {}",
+                frame.stream_id
+            );
+        }
+        FrameType::Handshake | FrameType::Ack | FrameType::Error => {
+            tracing::warn!(
+                "unexpected {:?} frame from client after handshake",
+                frame.frame_type
+            );
         }
     }
+}
 
-    if let Some(len) = headers.get("Content-Length") {
-        let len = len.parse::<usize>().unwrap();
-        while buffer.len() < header_end + len {
-            let n = stream.read(&mut tmp).await.unwrap();
-            if n == 0 {
+/// Passes response bytes on to the http client as they arrive, without waiting for it,
+/// returns `true` once the whole response has been handed over.
+fn relay_response(
+    tunnel_stream: &mut TunnelStream,
+    mut payload: Vec<u8>,
+    max_head_size: usize,
+) -> Result<bool, SendError<ResponseEvent>> {
+    while tunnel_stream.body.is_none() {
+        tunnel_stream.head.extend_from_slice(&payload);
+        let (response, head_len) = match parse_response(&tunnel_stream.head, max_head_size) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                // not something we can frame, pass it through until the client closes
+                tracing::warn!("malformed response head for {}: {e}", tunnel_stream.name);
+                payload = std::mem::take(&mut tunnel_stream.head);
+                tunnel_stream.body = Some(BodyTracker::new(BodyFraming::UntilClose));
                 break;
             }
-            buffer.extend_from_slice(&tmp[..n]);
+        };
+        payload = tunnel_stream.head.split_off(head_len);
+        let head = std::mem::take(&mut tunnel_stream.head);
+        tunnel_stream.deliver(ResponseEvent::Data(head))?;
+
+        // `100 Continue` and friends are followed by the final head
+        if !response.is_interim() {
+            let framing = BodyFraming::for_response(&response, tunnel_stream.head_request);
+            tunnel_stream.body = Some(BodyTracker::new(framing));
         }
-    } else if headers
-        .get("Transfer-Encoding")
-        .map(|v| v.to_ascii_lowercase())
-        == Some("chunked".into())
-    {
-        loop {
-            if buffer[header_end..].windows(5).any(|w| w == ZERO_DELIMETER_BYTES) {
-                break;
-            }
-            // Read more data
-            let n = stream.read(&mut tmp).await.unwrap();
-            if n == 0 {
-                eprintln!("Connection closed before chunked terminator");
-            }
-            buffer.extend_from_slice(&tmp[..n]);
+    }
+
+    let Some(body) = tunnel_stream.body.as_mut() else {
+        return Ok(false);
+    };
+    let used = body.feed(&payload);
+    let complete = body.is_complete();
+    if used > 0 {
+        payload.truncate(used);
+        tunnel_stream.deliver(ResponseEvent::Data(payload))?;
+    }
+    if complete {
+        tunnel_stream.deliver(ResponseEvent::End)?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Binds the public TCP and UDP ports a handshake asked for.
+async fn bind_tunnel_ports(
+    tcp_port: Option<u16>,
+    udp_port: Option<u16>,
+    settings: &TunnelSettings,
+) -> Result<
+    (
+        Option<(TcpListener, PortLease)>,
+        Option<(UdpSocket, PortLease)>,
+    ),
+    ErrorMessage,
+> {
+    let tcp = match tcp_port {
+        None => None,
+        Some(port) => {
+            let unavailable =
+                |message: &str| ErrorMessage::new(ErrorCode::TcpPortUnavailable, message);
+            let pool = settings
+                .tcp_ports
+                .as_ref()
+                .ok_or_else(|| unavailable("tcp tunnels are not enabled on this server"))?;
+            Some(pool.bind_tcp(port).await.map_err(|e| unavailable(&e))?)
         }
+    };
+    let udp = match udp_port {
+        None => None,
+        Some(port) => {
+            let unavailable =
+                |message: &str| ErrorMessage::new(ErrorCode::UdpPortUnavailable, message);
+            let pool = settings
+                .udp_ports
+                .as_ref()
+                .ok_or_else(|| unavailable("udp tunnels are not enabled on this server"))?;
+            Some(pool.bind_udp(port).await.map_err(|e| unavailable(&e))?)
+        }
+    };
+    Ok((tcp, udp))
+}
+
+async fn reject<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    addr: SocketAddr,
+    error: ErrorMessage,
+    shared_state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    shared_state.metrics.record_handshake_failure(error.code);
+    tracing::warn!(
+        "handshake from {addr} rejected: {} {}",
+        error.code,
+        error.message
+    );
+    write_frame(stream, &Frame::error(&error)).await?;
+    Ok(())
+}
 
-        if let Some(terminator_pos) = buffer[header_end..]
-            .windows(5)
-            .position(|w| w == ZERO_DELIMETER_BYTES)
-        {
-            let end_pos = header_end + terminator_pos + 5; // Include the terminator
-            buffer.truncate(end_pos);
+/// Reads the first frame of a connection, `Ok(None)` means the client left before sending it.
+async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    minimum_client_version: &str,
+) -> Result<Option<Handshake>, ErrorMessage> {
+    let frame = match read_frame(stream).await {
+        Ok(Some(frame)) => frame,
+        Ok(None) => return Ok(None),
+        Err(CodecError::UnsupportedVersion(version)) => {
+            return Err(ErrorMessage::new(
+                ErrorCode::UnsupportedProtocol,
+                &format!(
+                    "protocol version {version} is not supported, expected {PROTOCOL_VERSION}"
+                ),
+            ));
         }
-    } else {
-        // case error
-        // println!("Error: ERROR CASE");
-        // case return only header for example: 304, 201
+        Err(e) => {
+            return Err(ErrorMessage::new(
+                ErrorCode::MalformedHandshake,
+                &e.to_string(),
+            ));
+        }
+    };
+    if frame.frame_type != FrameType::Handshake {
+        return Err(ErrorMessage::new(
+            ErrorCode::MalformedHandshake,
+            "expected a handshake frame",
+        ));
     }
-    shared_state
-        .send_to_http_client(ticket.name.as_str(), buffer)
-        .await;
+
+    let handshake = Handshake::decode(&frame.payload)
+        .map_err(|e| ErrorMessage::new(ErrorCode::MalformedHandshake, &e.to_string()))?;
+    if !check_available_version(&handshake.client_version, minimum_client_version) {
+        return Err(ErrorMessage::new(
+            ErrorCode::ClientVersionTooOld,
+            &format!("client version must be at least {minimum_client_version}"),
+        ));
+    }
+    Ok(Some(handshake))
 }
 
 fn generate_name() -> String {
@@ -201,7 +828,7 @@ fn generate_name() -> String {
     name
 }
 
-fn parse_version(version_str: &str) -> Option<(u32, u32, u32)> {
+pub fn parse_version(version_str: &str) -> Option<(u32, u32, u32)> {
     let parts: Vec<&str> = version_str.split('.').collect();
     if parts.len() != 3 {
         return None;
@@ -238,22 +865,228 @@ mod tests {
     #[test]
     fn test_check_available_version_002() {
         let first_access = "0.0.2";
-        assert_eq!(check_available_version(first_access, "0.0.2"), true);
+        assert!(check_available_version(first_access, "0.0.2"));
     }
     #[test]
     fn test_check_available_version_001() {
         let first_access = "0.0.1";
-        assert_eq!(check_available_version(first_access, "0.0.2"), false);
+        assert!(!check_available_version(first_access, "0.0.2"));
     }
     #[test]
     fn test_check_available_version_003() {
         let first_access = "0.0.3";
-        assert_eq!(check_available_version(first_access, "0.0.2"), true);
+        assert!(check_available_version(first_access, "0.0.2"));
     }
     #[test]
     fn test_check_available_version_random_text() {
         let first_access = "fdsfdsfjds9]nsfdlksjdfl";
-        assert_eq!(check_available_version(first_access, "0.0.2"), false);
+        assert!(!check_available_version(first_access, "0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_http_client_is_queued() {
+        let (tx_frame, mut rx_frame) = mpsc::channel(4);
+        let (tx_http, mut rx_http) = mpsc::channel(1);
+        let tunnel_stream =
+            TunnelStream::new("myapp_tx-1".to_string(), StreamMode::Http, tx_http, false);
+        let mut streams = HashMap::from([(7, tunnel_stream)]);
+        let limits = Limits::default();
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n".to_vec();
+        process_frame(
+            Frame::data(7, head.clone()),
+            &tx_frame,
+            &mut streams,
+            &limits,
+        )
+        .await;
+
+        // nobody reads the head, the rest waits in the stream's own queue
+        for chunk in [b"abcde", b"fghij"] {
+            process_frame(
+                Frame::data(7, chunk.to_vec()),
+                &tx_frame,
+                &mut streams,
+                &limits,
+            )
+            .await;
+        }
+        assert!(streams.is_empty());
+        assert_eq!(rx_frame.recv().await, Some(Frame::close(7)));
+        assert_eq!(rx_http.recv().await, Some(ResponseEvent::Data(head)));
+        assert_eq!(
+            rx_http.recv().await,
+            Some(ResponseEvent::Data(b"abcde".to_vec()))
+        );
+        assert_eq!(
+            rx_http.recv().await,
+            Some(ResponseEvent::Data(b"fghij".to_vec()))
+        );
+        assert_eq!(rx_http.recv().await, Some(ResponseEvent::End));
+    }
+
+    #[tokio::test]
+    async fn test_http_client_far_behind_holds_the_tunnel() {
+        let (tx_frame, _rx_frame) = mpsc::channel(4);
+        let (tx_http, mut rx_http) = mpsc::channel(1);
+        let tunnel_stream =
+            TunnelStream::new("myapp_tcp-1".to_string(), StreamMode::Raw, tx_http, false);
+        let mut streams = HashMap::from([(7, tunnel_stream)]);
+        let limits = Limits {
+            response_buffer_size: 8,
+            ..Limits::default()
+        };
+        // one chunk fits the http client's queue and one the buffer
+        for chunk in [vec![1; 8], vec![2; 8]] {
+            process_frame(Frame::data(7, chunk), &tx_frame, &mut streams, &limits).await;
+        }
+        // the next goes past the buffer, the tunnel waits until the client reads
+        let held = process_frame(Frame::data(7, vec![3; 8]), &tx_frame, &mut streams, &limits);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), held)
+                .await
+                .is_err()
+        );
+        for byte in [1, 2, 3] {
+            assert_eq!(
+                rx_http.recv().await,
+                Some(ResponseEvent::Data(vec![byte; 8]))
+            );
+        }
+        assert!(streams.contains_key(&7));
+    }
+
+    #[tokio::test]
+    async fn test_datagrams_for_slow_peer_are_dropped() {
+        let (tx_frame, mut rx_frame) = mpsc::channel(4);
+        let (tx_http, mut rx_http) = mpsc::channel(1);
+        let tunnel_stream = TunnelStream::new(
+            "myapp_udp-1".to_string(),
+            StreamMode::Datagram,
+            tx_http,
+            false,
+        );
+        let mut streams = HashMap::from([(7, tunnel_stream)]);
+        let limits = Limits {
+            response_buffer_size: 8,
+            ..Limits::default()
+        };
+        for datagram in [[1; 8], [2; 8], [3; 8]] {
+            process_frame(
+                Frame::data(7, datagram.to_vec()),
+                &tx_frame,
+                &mut streams,
+                &limits,
+            )
+            .await;
+        }
+        // the session stays, only what did not fit is gone
+        assert!(streams.contains_key(&7));
+        assert!(rx_frame.try_recv().is_err());
+        assert_eq!(rx_http.recv().await, Some(ResponseEvent::Data(vec![1; 8])));
+        tokio::task::yield_now().await;
+        assert!(rx_http.try_recv().is_err());
+    }
+
+    fn test_settings() -> TunnelSettings {
+        TunnelSettings {
+            minimum_client_version: "0.0.2".to_string(),
+            limits: Limits::default(),
+            tcp_ports: None,
+            udp_ports: None,
+            udp_session_idle: Duration::from_secs(30),
+            udp_max_sessions: 16,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// A tunnel connection served over an in-memory pipe, returns the client end.
+    fn connect(shared_state: &SharedState, settings: TunnelSettings) -> tokio::io::DuplexStream {
+        use crate::auth::NoAuth;
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let shared_state = shared_state.clone();
+        tokio::spawn(async move {
+            let addr = ([127, 0, 0, 1], 5000).into();
+            TcpServer::handle_tcp_connection(
+                server,
+                addr,
+                None,
+                shared_state,
+                Arc::new(NoAuth),
+                settings,
+            )
+            .await
+            .is_ok()
+        });
+        client
+    }
+
+    fn test_state() -> SharedState {
+        use crate::capture::Captures;
+        use crate::custom_domain::CustomDomains;
+
+        SharedState::new(
+            Duration::from_secs(30),
+            CustomDomains::new(&[], false, ([127, 0, 0, 1], 53).into()),
+            Captures::new(0, 0),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_stream_is_closed() {
+        use crate::shared::{TicketRequestHttp, stream_name};
+
+        let shared_state = test_state();
+        let mut client = connect(&shared_state, test_settings());
+
+        let handshake = Handshake {
+            client_version: "0.0.2".to_string(),
+            subdomain: Some("myapp".to_string()),
+            ..Default::default()
+        };
+        let frame = Frame::new(FrameType::Handshake, 0, handshake.encode());
+        write_frame(&mut client, &frame).await.unwrap();
+        let ack = read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(ack.frame_type, FrameType::Ack);
+
+        let name = stream_name("myapp", "tx");
+        let (tx_http, rx_http) = mpsc::channel(4);
+        let pending = shared_state.register_http_client(name.clone(), tx_http);
+        let ticket = TicketRequestHttp {
+            name,
+            mode: StreamMode::Http,
+            head_request: false,
+            data: b"GET / HTTP/1.1\r\nHost: myapp.example.com\r\n\r\n".to_vec(),
+            body: None,
+        };
+        assert!(shared_state.send_to_tcp_client("myapp", ticket).await);
+        let open = read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(open.frame_type, FrameType::Open);
+
+        // the request timed out, the browser side lets go of the stream
+        drop(rx_http);
+        drop(pending);
+        let close = read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(close, Frame::close(open.stream_id));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let shared_state = test_state();
+        let settings = TunnelSettings {
+            handshake_timeout: Duration::from_millis(50),
+            ..test_settings()
+        };
+        let mut client = connect(&shared_state, settings);
+
+        // the client never sends its handshake
+        let frame = read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(frame.frame_type, FrameType::Error);
+        assert_eq!(&frame.payload[..2], &[0, ErrorCode::HandshakeTimeout as u8]);
+        let metrics = shared_state.metrics.render(&[]);
+        assert!(
+            metrics.contains("bindlocal_handshake_failures_total{code=\"handshake_timeout\"} 1")
+        );
     }
 
     #[test]
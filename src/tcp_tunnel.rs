@@ -0,0 +1,75 @@
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::config::Limits;
+use crate::http_server::pipe_upgraded;
+use crate::port_pool::PortLease;
+use crate::shared::{
+    ResponseEvent, SharedState, StreamMode, TicketRequestHttp, TunnelInfo, stream_name,
+};
+
+/// Accepts connections on a tunnel's public port and pipes each one through the tunnel as its own stream.
+pub async fn serve(
+    listener: TcpListener,
+    lease: PortLease,
+    tunnel: Arc<TunnelInfo>,
+    shared_state: SharedState,
+    limits: Limits,
+) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("tcp tunnel port {} stopped: {e}", lease.port());
+                return;
+            }
+        };
+        // looked up per connection, an admin may have renamed the tunnel
+        let client_id = tunnel.name();
+        tracing::info!("tcp tunnel [{client_id}] connection from {addr}");
+        tokio::spawn(forward(
+            socket,
+            client_id,
+            shared_state.clone(),
+            limits.clone(),
+            Vec::new(),
+        ));
+    }
+}
+
+/// Pipes one connection through the tunnel as a raw stream, `leftover` are bytes already read from it.
+pub async fn forward(
+    mut socket: TcpStream,
+    client_id: String,
+    shared_state: SharedState,
+    limits: Limits,
+    leftover: Vec<u8>,
+) {
+    let name = stream_name(&client_id, "tcp");
+    let (tx_http, rx_http) = mpsc::channel::<ResponseEvent>(limits.response_channel_capacity);
+    let (tx_body, rx_body) = mpsc::channel::<Vec<u8>>(limits.body_channel_capacity);
+    let _pending = shared_state.register_http_client(name.clone(), tx_http);
+
+    let ticket = TicketRequestHttp {
+        name: name.clone(),
+        mode: StreamMode::Raw,
+        head_request: false,
+        // the client opens its local connection right away, either side may speak first
+        data: Vec::new(),
+        body: Some(rx_body),
+    };
+    if shared_state.send_to_tcp_client(&client_id, ticket).await {
+        let piped = pipe_upgraded(
+            &mut socket,
+            rx_http,
+            tx_body,
+            leftover,
+            limits.read_chunk_size,
+        )
+        .await;
+        if let Err(e) = piped {
+            tracing::warn!("tcp tunnel [{client_id}] connection failed: {e}");
+        }
+    }
+}
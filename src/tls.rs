@@ -0,0 +1,341 @@
+use rustls::RootCertStore;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::auth::Identity;
+use crate::watch::{WATCH_INTERVAL, watch_file};
+
+/// A PEM certificate chain and its private key on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Picks the certificate matching the SNI name of each handshake, wildcards included.
+/// The first certificate is used when nothing matches or the client sent no name.
+#[derive(Debug)]
+pub struct SniResolver {
+    paths: Vec<CertPaths>,
+    certs: RwLock<Vec<Arc<CertifiedKey>>>,
+}
+
+impl SniResolver {
+    pub fn load(paths: Vec<CertPaths>) -> Result<Self, Box<dyn std::error::Error>> {
+        if paths.is_empty() {
+            return Err("no TLS certificate configured".into());
+        }
+        let certs = load_all(&paths)?;
+        Ok(Self {
+            paths,
+            certs: RwLock::new(certs),
+        })
+    }
+
+    /// Loads the certificates and reloads them whenever one of the files changes.
+    pub fn load_and_watch(paths: Vec<CertPaths>) -> Result<Arc<Self>, Box<dyn std::error::Error>> {
+        let resolver = Arc::new(Self::load(paths)?);
+        for path in resolver.watched_files() {
+            let resolver = resolver.clone();
+            watch_file(path, WATCH_INTERVAL, move || resolver.reload());
+        }
+        Ok(resolver)
+    }
+
+    fn watched_files(&self) -> Vec<PathBuf> {
+        self.paths
+            .iter()
+            .flat_map(|paths| [paths.cert.clone(), paths.key.clone()])
+            .collect()
+    }
+
+    /// Swaps in the certificates from disk, a broken file keeps the previous ones serving.
+    pub fn reload(&self) {
+        match load_all(&self.paths) {
+            Ok(certs) => {
+                if let Ok(mut current) = self.certs.write() {
+                    *current = certs;
+                }
+                tracing::info!("reloaded {} TLS certificate(s)", self.paths.len());
+            }
+            Err(e) => tracing::warn!("keeping the current TLS certificates: {e}"),
+        }
+    }
+
+    fn find(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().ok()?;
+        let server_name = server_name.and_then(|name| ServerName::try_from(name).ok());
+        if let Some(server_name) = server_name
+            && let Some(cert) = certs.iter().find(|cert| covers(cert, &server_name))
+        {
+            return Some(cert.clone());
+        }
+        certs.first().cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+/// Server side TLS settings for the public HTTP listener.
+pub fn server_config(
+    resolver: Arc<SniResolver>,
+) -> Result<Arc<rustls::ServerConfig>, Box<dyn std::error::Error>> {
+    let mut config =
+        rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Server side TLS settings for the tunnel listener, with a `client_ca` every
+/// client has to present a certificate signed by it.
+pub fn tunnel_server_config(
+    resolver: Arc<SniResolver>,
+    client_ca: Option<&Path>,
+) -> Result<Arc<rustls::ServerConfig>, Box<dyn std::error::Error>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let config = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .map_err(|e| format!("cannot read client CA {}: {e}", path.display()))?
+            {
+                roots.add(cert?)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config.with_cert_resolver(resolver)))
+}
+
+/// The tenant named by a verified client certificate: its common name,
+/// or the first DNS name when the subject has none.
+pub fn peer_identity(certs: &[CertificateDer<'_>]) -> Option<Identity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(certs.first()?).ok()?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok())
+        .map(str::to_string);
+    let name = common_name.or_else(|| {
+        let san = cert.subject_alternative_name().ok()??;
+        san.value.general_names.iter().find_map(|name| match name {
+            x509_parser::extensions::GeneralName::DNSName(dns) => Some(dns.to_string()),
+            _ => None,
+        })
+    })?;
+    Some(Identity { name })
+}
+
+fn load_all(paths: &[CertPaths]) -> Result<Vec<Arc<CertifiedKey>>, Box<dyn std::error::Error>> {
+    paths
+        .iter()
+        .map(|paths| load_certified_key(paths).map(Arc::new))
+        .collect()
+}
+
+fn load_certified_key(paths: &CertPaths) -> Result<CertifiedKey, Box<dyn std::error::Error>> {
+    let cert_file = paths.cert.display();
+    let chain = CertificateDer::pem_file_iter(&paths.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("cannot read certificate {cert_file}: {e}"))?;
+    if chain.is_empty() {
+        return Err(format!("no certificate in {cert_file}").into());
+    }
+    let key = PrivateKeyDer::from_pem_file(&paths.key)
+        .map_err(|e| format!("cannot read private key {}: {e}", paths.key.display()))?;
+    let signing_key = ring::sign::any_supported_type(&key)?;
+
+    let certified = CertifiedKey::new(chain, signing_key);
+    certified
+        .keys_match()
+        .map_err(|e| format!("{cert_file} does not match its key: {e}"))?;
+    Ok(certified)
+}
+
+fn covers(cert: &CertifiedKey, server_name: &ServerName<'_>) -> bool {
+    cert.end_entity_cert()
+        .ok()
+        .and_then(|der| webpki::EndEntityCert::try_from(der).ok())
+        .is_some_and(|cert| cert.verify_is_valid_for_subject_name(server_name).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &std::path::Path, file: &str, names: &[&str]) -> CertPaths {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+        let paths = CertPaths {
+            cert: dir.join(format!("{file}.crt")),
+            key: dir.join(format!("{file}.key")),
+        };
+        std::fs::write(&paths.cert, generated.cert.pem()).unwrap();
+        std::fs::write(&paths.key, generated.key_pair.serialize_pem()).unwrap();
+        paths
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("connl-tls-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn der_of(cert: &CertifiedKey) -> Vec<u8> {
+        cert.end_entity_cert().unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_sni_picks_matching_certificate() {
+        let dir = temp_dir("sni");
+        let wildcard = write_cert(&dir, "wildcard", &["*.example.com"]);
+        let other = write_cert(&dir, "other", &["*.other.test", "other.test"]);
+        let resolver = SniResolver::load(vec![wildcard, other]).unwrap();
+        let certs = resolver.certs.read().unwrap().clone();
+
+        let pick = |name: Option<&str>| der_of(&resolver.find(name).unwrap());
+        assert_eq!(pick(Some("app.example.com")), der_of(&certs[0]));
+        assert_eq!(pick(Some("app.other.test")), der_of(&certs[1]));
+        assert_eq!(pick(Some("other.test")), der_of(&certs[1]));
+        // no match and no SNI fall back to the first certificate
+        assert_eq!(pick(Some("a.b.example.com")), der_of(&certs[0]));
+        assert_eq!(pick(None), der_of(&certs[0]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_old_certificate_on_error() {
+        let dir = temp_dir("reload");
+        let paths = write_cert(&dir, "site", &["*.example.com"]);
+        let resolver = SniResolver::load(vec![paths.clone()]).unwrap();
+        let before = der_of(&resolver.find(None).unwrap());
+
+        std::fs::write(&paths.cert, "garbage").unwrap();
+        resolver.reload();
+        assert_eq!(der_of(&resolver.find(None).unwrap()), before);
+
+        write_cert(&dir, "site", &["*.example.com"]);
+        resolver.reload();
+        assert_ne!(der_of(&resolver.find(None).unwrap()), before);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn client_cert(
+        common_name: Option<&str>,
+        names: &[&str],
+    ) -> (rcgen::Certificate, rcgen::KeyPair) {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let mut params = rcgen::CertificateParams::new(names).unwrap();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        if let Some(common_name) = common_name {
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, common_name);
+        }
+        let key = rcgen::KeyPair::generate().unwrap();
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let (cert, _) = client_cert(Some("acme"), &["laptop.acme.test"]);
+        assert_eq!(
+            peer_identity(&[cert.der().clone()]).unwrap().name,
+            "acme".to_string()
+        );
+        let (cert, _) = client_cert(None, &["laptop.acme.test"]);
+        assert_eq!(
+            peer_identity(&[cert.der().clone()]).unwrap().name,
+            "laptop.acme.test".to_string()
+        );
+        assert!(peer_identity(&[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_identifies_client() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = temp_dir("mtls");
+        let server = write_cert(&dir, "server", &["tunnel.example.com"]);
+
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let mut client_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        client_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "acme");
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let resolver = Arc::new(SniResolver::load(vec![server.clone()]).unwrap());
+        let config = tunnel_server_config(resolver, Some(&dir.join("ca.crt"))).unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(config);
+
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&server.cert).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_client_auth_cert(
+                    vec![client.der().clone()],
+                    PrivateKeyDer::try_from(client_key.serialize_der()).unwrap(),
+                )
+                .unwrap();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server_task = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_io).await.unwrap();
+            let identity = peer_identity(stream.get_ref().1.peer_certificates().unwrap());
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            identity
+        });
+        let name = ServerName::try_from("tunnel.example.com").unwrap();
+        let mut stream = connector.connect(name, client_io).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+
+        assert_eq!(server_task.await.unwrap().unwrap().name, "acme".to_string());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_mismatched_key_is_refused() {
+        let dir = temp_dir("mismatch");
+        let first = write_cert(&dir, "first", &["a.example.com"]);
+        let second = write_cert(&dir, "second", &["b.example.com"]);
+        let mixed = CertPaths {
+            cert: first.cert,
+            key: second.key,
+        };
+        assert!(SniResolver::load(vec![mixed]).is_err());
+        assert!(SniResolver::load(Vec::new()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc;
+
+use crate::config::Limits;
+use crate::port_pool::PortLease;
+use crate::shared::{
+    ResponseEvent, SharedState, StreamMode, TicketRequestHttp, TunnelInfo, stream_name,
+};
+
+const MAX_DATAGRAM: usize = 65535;
+// how often idle sessions are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// one peer address, carried as its own stream over the tunnel
+struct Session {
+    tx_datagram: mpsc::Sender<Vec<u8>>,
+    last_seen: Arc<Mutex<Instant>>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    fn is_idle(&self, idle: Duration) -> bool {
+        self.last_seen.lock().unwrap().elapsed() >= idle || self.tx_datagram.is_closed()
+    }
+}
+
+/// Receives datagrams on a tunnel's public UDP port, every peer address becomes a session that
+/// ends after `idle` without traffic either way. At most `max_sessions` peers are served at once.
+pub async fn serve(
+    socket: UdpSocket,
+    lease: PortLease,
+    tunnel: Arc<TunnelInfo>,
+    shared_state: SharedState,
+    limits: Limits,
+    idle: Duration,
+    max_sessions: usize,
+) {
+    let socket = Arc::new(socket);
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        select! {
+            received = socket.recv_from(&mut buf) => {
+                let (n, peer) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::warn!("udp tunnel port {} receive failed: {e}", lease.port());
+                        continue;
+                    }
+                };
+                if sessions.get(&peer).is_some_and(|session| session.tx_datagram.is_closed()) {
+                    sessions.remove(&peer);
+                }
+                let full = sessions.len() >= max_sessions;
+                let session = match sessions.entry(peer) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    // spoofed source addresses must not open streams without end
+                    Entry::Vacant(_) if full => {
+                        tracing::debug!(
+                            "udp tunnel port {} is full, dropped a datagram from {peer}",
+                            lease.port()
+                        );
+                        continue;
+                    }
+                    Entry::Vacant(entry) => {
+                        let client_id = tunnel.name();
+                        let Some(session) =
+                            open_session(&socket, peer, &client_id, &shared_state, &limits).await
+                        else {
+                            continue;
+                        };
+                        tracing::info!("udp tunnel [{client_id}] session from {peer}");
+                        entry.insert(session)
+                    }
+                };
+                session.touch();
+                // like the network itself, drop what the tunnel cannot take right now
+                let _ = session.tx_datagram.try_send(buf[..n].to_vec());
+            },
+            _ = sweep.tick() => {
+                // dropping a session closes its stream, the client sees a close frame
+                sessions.retain(|peer, session| {
+                    let keep = !session.is_idle(idle);
+                    if !keep {
+                        tracing::info!("udp tunnel [{}] session from {peer} ended", tunnel.name());
+                    }
+                    keep
+                });
+            },
+        }
+    }
+}
+
+async fn open_session(
+    socket: &Arc<UdpSocket>,
+    peer: SocketAddr,
+    client_id: &str,
+    shared_state: &SharedState,
+    limits: &Limits,
+) -> Option<Session> {
+    let name = stream_name(client_id, "udp");
+    let (tx_http, mut rx_http) = mpsc::channel::<ResponseEvent>(limits.response_channel_capacity);
+    let (tx_datagram, rx_datagram) = mpsc::channel::<Vec<u8>>(limits.body_channel_capacity);
+    let pending = shared_state.register_http_client(name.clone(), tx_http);
+
+    let ticket = TicketRequestHttp {
+        name: name.clone(),
+        mode: StreamMode::Datagram,
+        head_request: false,
+        // the open frame tells the client where the datagrams come from
+        data: peer.to_string().into_bytes(),
+        body: Some(rx_datagram),
+    };
+    if !shared_state.send_to_tcp_client(client_id, ticket).await {
+        return None;
+    }
+
+    let session = Session {
+        tx_datagram,
+        last_seen: Arc::new(Mutex::new(Instant::now())),
+    };
+    let last_seen = session.last_seen.clone();
+    let socket = socket.clone();
+    tokio::spawn(async move {
+        let _pending = pending;
+        // every data frame from the client is one datagram back to the peer
+        while let Some(ResponseEvent::Data(datagram)) = rx_http.recv().await {
+            *last_seen.lock().unwrap() = Instant::now();
+            if let Err(e) = socket.send_to(&datagram, peer).await {
+                tracing::warn!("udp reply to {peer} failed: {e}");
+            }
+        }
+    });
+    Some(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_idle() {
+        let (tx_datagram, rx) = mpsc::channel::<Vec<u8>>(1);
+        let session = Session {
+            tx_datagram,
+            last_seen: Arc::new(Mutex::new(Instant::now() - Duration::from_secs(5))),
+        };
+        assert!(session.is_idle(Duration::from_secs(5)));
+        assert!(!session.is_idle(Duration::from_secs(60)));
+        session.touch();
+        assert!(!session.is_idle(Duration::from_secs(5)));
+
+        // a stream the tunnel closed counts as idle right away
+        drop(rx);
+        assert!(session.is_idle(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn test_session_limit() {
+        use crate::capture::Captures;
+        use crate::config::PortRange;
+        use crate::custom_domain::CustomDomains;
+        use crate::port_pool::PortPool;
+
+        let shared_state = SharedState::new(
+            Duration::from_secs(30),
+            CustomDomains::new(&[], false, ([127, 0, 0, 1], 53).into()),
+            Captures::new(0, 0),
+        );
+        let (tx_ticket, mut rx_ticket) = mpsc::unbounded_channel();
+        let tunnel = Arc::new(TunnelInfo::new(
+            "myapp",
+            "acme",
+            "127.0.0.1:5000".parse().unwrap(),
+        ));
+        shared_state
+            .register_tcp_client(tx_ticket, tunnel.clone())
+            .await;
+
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let pool = PortPool::new(
+            [127, 0, 0, 1].into(),
+            PortRange {
+                start: port,
+                end: port,
+            },
+        );
+        let (socket, lease) = pool.bind_udp(port).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(serve(
+            socket,
+            lease,
+            tunnel,
+            shared_state,
+            Limits::default(),
+            Duration::from_secs(60),
+            1,
+        ));
+
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        first.send_to(b"one", addr).await.unwrap();
+        let ticket = rx_ticket.recv().await.unwrap();
+        assert_eq!(
+            ticket.data,
+            first.local_addr().unwrap().to_string().as_bytes()
+        );
+
+        // a second peer finds the tunnel full, its datagram opens nothing
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        second.send_to(b"two", addr).await.unwrap();
+        let opened = tokio::time::timeout(Duration::from_millis(100), rx_ticket.recv()).await;
+        assert!(opened.is_err());
+    }
+}
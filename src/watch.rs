@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Polls the modification time of `path` and calls `on_change` whenever it moves.
+pub fn watch_file<F>(path: PathBuf, interval: Duration, on_change: F)
+where
+    F: Fn() + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let current = modified(&path);
+            if current.is_some() && current != last_modified {
+                last_modified = current;
+                on_change();
+            }
+        }
+    });
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}